@@ -1,9 +1,10 @@
 use ic_cdk::export::candid::{CandidType, Deserialize};
-use ic_cdk::storage::{stable_restore, stable_save};
-use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade, query, update};
+use ic_cdk_macros::{heartbeat, init, query, update};
 
-use ic_event_hub::event_hub::EventHub;
-use ic_event_hub::{implement_event_emitter, implement_subscribe, implement_unsubscribe};
+use ic_event_hub::{
+    implement_event_catalog, implement_event_emitter, implement_get_subscribers,
+    implement_subscribe, implement_subscription_epoch, implement_unsubscribe,
+};
 use ic_event_hub_macros::Event;
 
 // ------------- MAIN LOGIC -------------------
@@ -27,9 +28,16 @@ pub struct MirrorEvent {
     pub data: Vec<u8>,
 }
 
-implement_event_emitter!(1_000_000_000 * 25, 1024 * 1024);
+implement_event_emitter!(
+    1_000_000_000 * 25,
+    1024 * 1024,
+    upgrade = (take_state, put_state)
+);
 implement_subscribe!();
 implement_unsubscribe!();
+implement_get_subscribers!();
+implement_subscription_epoch!();
+implement_event_catalog!();
 
 #[heartbeat]
 pub fn tick() {
@@ -56,19 +64,10 @@ fn init() {
     }
 }
 
-#[pre_upgrade]
-fn pre_upgrade_hook() {
-    let canister_state = unsafe { STATE.take() };
-    let event_hub_state = _take_event_hub_state();
-
-    stable_save((canister_state, event_hub_state)).expect("Unable to stable save");
+fn take_state() -> Option<RequestCounter> {
+    unsafe { STATE.take() }
 }
 
-#[post_upgrade]
-fn post_upgrade_hook() {
-    let (canister_state, event_hub_state): (Option<RequestCounter>, Option<EventHub>) =
-        stable_restore().expect("Unable to stable restore");
-
-    unsafe { STATE = canister_state };
-    _put_event_hub_state(event_hub_state);
+fn put_state(state: Option<RequestCounter>) {
+    unsafe { STATE = state }
 }
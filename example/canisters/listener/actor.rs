@@ -1,10 +1,10 @@
-use ic_cdk::export::candid::{export_service, Principal};
+use ic_cdk::export::candid::{export_service, CandidType, Deserialize, Principal};
 use ic_cdk::{print, trap};
-use ic_cdk_macros::{init, query, update};
+use ic_cdk_macros::{heartbeat, init, query, update};
 
-use ic_event_hub::api::IEventHubClient;
-use ic_event_hub::types::{CallbackInfo, EventFilter, SubscribeRequest};
+use ic_event_hub::api::{verify_subscriptions, IEventHubClient, SubscribeRequestBuilder};
 use ic_event_hub::types::{Event, IEvent};
+use ic_event_hub::{implement_event_callback, implement_event_listener};
 use ic_event_hub_macros::Event;
 
 // ------------- MAIN LOGIC -------------------
@@ -28,21 +28,30 @@ pub struct MirrorEvent {
 
 #[update]
 async fn start_listening() {
+    let req = SubscribeRequestBuilder::new()
+        .callback(&MirrorEventFilter {}, "events_callback")
+        .build_checked(&__export_service())
+        .unwrap_or_else(|e| trap(e.as_str()));
+
     get_state()
         .emitter_canister_id
-        .subscribe(SubscribeRequest {
-            callbacks: vec![CallbackInfo {
-                filter: EventFilter::empty(),
-                method_name: String::from("events_callback"),
-            }],
-        })
+        .subscribe(req)
         .await
         .ok()
         .unwrap();
 }
 
-#[update]
-fn events_callback(events: Vec<Event>) {
+#[heartbeat]
+fn tick() {
+    ic_cdk::block_on(async {
+        verify_subscriptions(1_000_000_000 * 60).await;
+    });
+}
+
+implement_event_callback!(events_callback, handle_events);
+implement_event_listener!(upgrade = (take_state, put_state));
+
+fn handle_events(events: Vec<Event>) {
     get_state().batches_received += 1;
 
     for event in events {
@@ -57,6 +66,7 @@ fn events_callback(events: Vec<Event>) {
 
 // ------------------ STATE ----------------------
 
+#[derive(CandidType, Deserialize)]
 pub struct RequestCounterMirror {
     pub emitter_canister_id: Principal,
     pub events_received: u64,
@@ -79,3 +89,13 @@ fn init(emitter_canister_id: Principal) {
         });
     }
 }
+
+fn take_state() -> Option<RequestCounterMirror> {
+    unsafe { STATE.take() }
+}
+
+fn put_state(state: Option<RequestCounterMirror>) {
+    unsafe { STATE = state }
+}
+
+export_service!();
@@ -0,0 +1,115 @@
+//! A standalone canister that receives event batches from many emitters and delivers them to
+//! listeners, so emitters don't have to pay for the fan-out themselves
+//!
+//! Emitters forward their events with `EventHub::forward_to_broker()` (one call per batch),
+//! listeners subscribe to the broker with `IEventHubClient` the same way they would subscribe to
+//! any emitter. Each delivered event keeps its original emitter in the `EVENT_EMITTER_FIELD`
+//! topic, so listeners could also filter by it with `EventFilter::from_emitter()`.
+//!
+//! The same canister could serve as a relay of a single large emitter: the emitter adds it with
+//! `EventHub::add_relay()` and passes listener subscriptions to it, while the broker is deployed
+//! with the emitter as its `upstream`.
+//!
+//! Only the `upstream` and the `publishers` passed on init are allowed to publish events.
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use ic_cdk::export::Principal;
+use ic_cdk::storage::{stable_restore, stable_save};
+use ic_cdk::{caller, trap};
+use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade, update};
+
+use ic_event_hub::transaction::EventTransaction;
+use ic_event_hub::types::Event;
+use ic_event_hub::upgrade::VersionedEventHubState;
+use ic_event_hub::{
+    implement_event_emitter, implement_get_subscribers, implement_relay, implement_subscribe,
+    implement_subscription_epoch, implement_unsubscribe,
+};
+
+// ------------- MAIN LOGIC -------------------
+
+#[update]
+fn publish(events: Vec<Event>) {
+    let emitter = caller();
+
+    let allowed = PUBLISHERS.with(|publishers| publishers.borrow().contains(&emitter))
+        || with_event_hub(|hub| hub.is_relay_upstream(&emitter));
+
+    if !allowed {
+        trap(format!("Canister {} is not allowed to publish events", emitter).as_str());
+    }
+
+    let mut transaction = EventTransaction::new();
+
+    for mut event in events {
+        event.set_emitter(emitter);
+        transaction.emit(event);
+    }
+
+    // the whole batch is rejected, so the emitter sees the failure in its delivery report
+    if let Err(e) = commit_events(transaction) {
+        trap(format!("Unable to publish events - {:?}", e).as_str());
+    }
+}
+
+// ------------------ EVENT HUB ------------------
+
+implement_event_emitter!(1_000_000_000, 1024 * 1024);
+implement_subscribe!();
+implement_unsubscribe!();
+implement_get_subscribers!();
+implement_subscription_epoch!();
+implement_relay!();
+
+#[heartbeat]
+pub fn tick() {
+    send_events();
+}
+
+#[init]
+fn init(
+    batch_making_duration_nano: Option<u64>,
+    batch_max_size_bytes: Option<u64>,
+    upstream: Option<Principal>,
+    publishers: Option<Vec<Principal>>,
+) {
+    PUBLISHERS.with(|it| it.replace(publishers.unwrap_or_default().into_iter().collect()));
+
+    with_event_hub(|hub| {
+        if let Some(duration) = batch_making_duration_nano {
+            hub.set_batch_making_duration_nano(duration);
+        }
+
+        if let Some(max_size) = batch_max_size_bytes {
+            hub.set_max_batch_size(max_size as usize);
+        }
+
+        if let Some(upstream) = upstream {
+            hub.add_relay_upstream(upstream);
+        }
+    });
+}
+
+// ------------------ STATE ----------------------
+
+thread_local! {
+    static PUBLISHERS: RefCell<BTreeSet<Principal>> = RefCell::new(BTreeSet::new());
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let publishers = PUBLISHERS.with(|it| it.take());
+
+    stable_save((_save_event_hub_state(), publishers)).expect("Unable to stable save");
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let (event_hub_state, publishers): (VersionedEventHubState, BTreeSet<Principal>) =
+        stable_restore().expect("Unable to stable restore");
+
+    PUBLISHERS.with(|it| it.replace(publishers));
+    _restore_event_hub_state(event_hub_state);
+}
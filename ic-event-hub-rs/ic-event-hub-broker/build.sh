@@ -0,0 +1,8 @@
+#!/usr/bin/env bash
+
+SCRIPT=$(readlink -f "$0")
+SCRIPTPATH=$(dirname "$SCRIPT")
+cd "$SCRIPTPATH" || exit
+
+cargo build --target wasm32-unknown-unknown --release --package ic-event-hub-broker && \
+ ic-cdk-optimizer ./target/wasm32-unknown-unknown/release/ic_event_hub_broker.wasm -o ./target/wasm32-unknown-unknown/release/ic-event-hub-broker-opt.wasm
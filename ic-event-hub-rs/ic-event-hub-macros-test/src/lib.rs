@@ -1,7 +1,9 @@
 #[cfg(test)]
 mod tests {
+    use ic_cdk::export::candid::parser::value::IDLValue;
+    use ic_cdk::export::candid::{encode_one, Nat};
+    use ic_event_hub::types::{CompactEvent, EventFilter, IEvent, IEventFilter};
     use ic_event_hub::{implement_event_emitter, implement_subscribe, implement_unsubscribe};
-    use ic_event_hub::types::{IEvent, IEventFilter};
     use ic_event_hub_macros::Event;
 
     implement_event_emitter!();
@@ -19,6 +21,20 @@ mod tests {
         pub b: String,
     }
 
+    #[derive(Event, Debug, PartialEq, Eq)]
+    struct TransferEvent {
+        #[topic]
+        pub amount: u32,
+    }
+
+    #[derive(Event)]
+    #[event(retain)]
+    struct PriceEvent {
+        #[topic(key)]
+        pub token: String,
+        pub price: u64,
+    }
+
     #[test]
     fn events_serialization_works_fine() {
         let event = TestEvent {
@@ -31,6 +47,39 @@ mod tests {
 
         assert_eq!(event, event_de);
         assert_eq!(event_ser.get_name(), String::from("TestEvent"));
+        assert!(!event.is_retained());
+
+        let price = PriceEvent {
+            token: String::from("ICP"),
+            price: 10,
+        };
+        assert!(price.is_retained());
+
+        let price_ser = price.to_event();
+        assert!(price_ser.get_compaction_key().is_some());
+        assert!(event_ser.get_compaction_key().is_none());
+    }
+
+    #[test]
+    fn compact_events_serialization_works_fine() {
+        let event = TestEvent {
+            a: 10,
+            b: String::from("kek"),
+        };
+
+        let compact = event.to_compact_event();
+        assert!(encode_one(&compact).unwrap().len() < encode_one(&event.to_event()).unwrap().len());
+        assert_eq!(TestEvent::from_compact_event(compact.clone()), Some(event));
+
+        // events of other types are skipped
+        assert!(PriceEvent::from_compact_event(compact).is_none());
+
+        let event = TestEvent {
+            a: 20,
+            b: String::from("lol"),
+        };
+        let full = CompactEvent::Full(event.to_event());
+        assert_eq!(TestEvent::from_compact_event(full), Some(event));
     }
 
     #[test]
@@ -44,4 +93,29 @@ mod tests {
 
         assert_eq!(filter.b, filter_de.b);
     }
+
+    #[test]
+    fn dynamic_filters_match_derived_events() {
+        let event = TransferEvent { amount: 5 };
+        let event_ser = event.to_event();
+
+        // topics are sent the same way older versions did
+        assert!(event_ser
+            .topics
+            .iter()
+            .any(|topic| topic.value == encode_one(5u32).unwrap()));
+
+        // built with other candid types, as a client in another language would do
+        let filter = EventFilter::for_event("TransferEvent")
+            .with_topic("amount", &IDLValue::Nat(Nat::from(5u64)));
+        assert_eq!(
+            filter,
+            TransferEventFilter { amount: Some(5) }
+                .to_event_filter()
+                .canonicalize()
+        );
+        assert!(filter.0.is_subset(&event_ser.canonical_topics()));
+
+        assert_eq!(TransferEvent::from_event(event_ser), event);
+    }
 }
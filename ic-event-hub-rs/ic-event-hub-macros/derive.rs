@@ -3,7 +3,9 @@ use std::collections::BTreeSet;
 
 use proc_macro2::{Ident, Span};
 use quote::quote;
-use syn::{parse, Data, DeriveInput, Fields, Ident as SynIdent, Type};
+use syn::{
+    parse, Attribute, Data, DeriveInput, Fields, Ident as SynIdent, Lit, Meta, NestedMeta, Type,
+};
 
 pub fn event_macro_impl(input: TokenStream) -> TokenStream {
     let ast: DeriveInput = parse(input).unwrap();
@@ -12,9 +14,13 @@ pub fn event_macro_impl(input: TokenStream) -> TokenStream {
     let name_str = name.to_string();
 
     let filter_name = SynIdent::new(&format!("{}Filter", name), Span::call_site());
+    let EventAttrs { version, retain } = parse_event_attrs(&ast.attrs);
 
     let mut topics: Vec<(Ident, Type, String)> = vec![];
-    let mut values: Vec<(Ident, String)> = vec![];
+    let mut values: Vec<(Ident, Type, String)> = vec![];
+    // all the fields in the order of their declaration, as they are encoded in compact events
+    let mut fields: Vec<Ident> = vec![];
+    let mut key: Option<Ident> = None;
 
     match ast.data {
         Data::Struct(ref data_struct) => {
@@ -33,11 +39,20 @@ pub fn event_macro_impl(input: TokenStream) -> TokenStream {
                         })
                         .collect();
                     let item = field.ident.clone().unwrap();
+                    fields.push(item.clone());
+
+                    if field.attrs.iter().any(is_key_topic_attr) {
+                        if key.is_some() {
+                            panic!("Only one field could be marked with #[topic(key)]");
+                        }
+
+                        key = Some(item.clone());
+                    }
 
                     if field_attrs.contains("topic") {
                         topics.push((item.clone(), field.ty.clone(), item.to_string()))
                     } else {
-                        values.push((item.clone(), item.to_string()))
+                        values.push((item.clone(), field.ty.clone(), item.to_string()))
                     }
                 }
             }
@@ -56,9 +71,19 @@ pub fn event_macro_impl(input: TokenStream) -> TokenStream {
         }
     });
 
+    let key_event_ser = match &key {
+        Some(field) => quote! {
+            res.insert(ic_event_hub::types::EventField {
+                name: String::from(ic_event_hub::EVENT_KEY_FIELD),
+                value: ic_cdk::export::candid::encode_one(&self.#field).unwrap()
+            });
+        },
+        None => quote!(),
+    };
+
     let topics_event_de = topics.iter().fold(quote!(), |es, (field, _, field_name)| {
         quote! {
-            #es #field: ic_cdk::export::candid::decode_one(fields.get(#field_name).unwrap()).unwrap(),
+            #es #field: ic_event_hub::topic::decode_topic(fields.get(#field_name).unwrap()).unwrap(),
         }
     });
 
@@ -82,11 +107,11 @@ pub fn event_macro_impl(input: TokenStream) -> TokenStream {
 
     let topics_filter_de = topics.iter().fold(quote!(), |es, (field, _, field_name)| {
         quote! {
-            #es #field: Some(ic_cdk::export::candid::decode_one(fields.get(#field_name).unwrap()).unwrap()),
+            #es #field: Some(ic_event_hub::topic::decode_topic(fields.get(#field_name).unwrap()).unwrap()),
         }
     });
 
-    let values_ser = values.iter().fold(quote!(), |es, (field, field_name)| {
+    let values_ser = values.iter().fold(quote!(), |es, (field, _, field_name)| {
         quote! {
             #es ic_event_hub::types::EventField {
                 name: String::from(#field_name),
@@ -95,12 +120,27 @@ pub fn event_macro_impl(input: TokenStream) -> TokenStream {
         }
     });
 
-    let values_de = values.iter().fold(quote!(), |es, (field, field_name)| {
+    let values_de = values.iter().fold(quote!(), |es, (field, _, field_name)| {
         quote! {
             #es #field: ic_cdk::export::candid::decode_one(fields.get(#field_name).unwrap()).unwrap(),
         }
     });
 
+    let compact_ser = fields.iter().fold(quote!(), |es, field| {
+        quote! {
+            #es builder.arg(&self.#field).unwrap();
+        }
+    });
+
+    let compact_de = fields.iter().fold(quote!(), |es, field| {
+        quote! {
+            #es #field: de.get_value().ok()?,
+        }
+    });
+
+    let topics_schema = fields_schema(&topics);
+    let values_schema = fields_schema(&values);
+
     // Create the new structure
     let gen = quote! {
         impl ic_event_hub::types::IEvent for #name {
@@ -111,6 +151,7 @@ pub fn event_macro_impl(input: TokenStream) -> TokenStream {
                     value: ic_cdk::export::candid::encode_one(#name_str).unwrap()
                 });
                 #topics_event_ser
+                #key_event_ser
 
                 ic_event_hub::types::Event {
                     topics: res,
@@ -132,6 +173,66 @@ pub fn event_macro_impl(input: TokenStream) -> TokenStream {
                     #values_de
                 }
             }
+
+            fn is_retained(&self) -> bool {
+                #retain
+            }
+
+            fn event_schema_fn(&self) -> Option<fn() -> ic_event_hub::types::EventSchema> {
+                Some(<Self as ic_event_hub::types::IEventSchema>::event_schema)
+            }
+
+            fn to_compact_event(&self) -> ic_event_hub::types::CompactEvent {
+                let mut builder = ic_cdk::export::candid::ser::IDLBuilder::new();
+                #compact_ser
+
+                ic_event_hub::types::CompactEvent::Fields {
+                    name: String::from(#name_str),
+                    values: builder.serialize_to_vec().unwrap(),
+                }
+            }
+
+            fn from_compact_event(event: ic_event_hub::types::CompactEvent) -> Option<Self> {
+                match event {
+                    ic_event_hub::types::CompactEvent::Fields { name, values } => {
+                        if name != #name_str {
+                            return None;
+                        }
+
+                        let mut de = ic_cdk::export::candid::de::IDLDeserialize::new(&values).ok()?;
+                        let res = Self {
+                            #compact_de
+                        };
+                        de.done().ok()?;
+
+                        Some(res)
+                    }
+                    ic_event_hub::types::CompactEvent::Full(event) => {
+                        let name: String = event
+                            .topics
+                            .iter()
+                            .find(|topic| topic.name == *ic_event_hub::EVENT_NAME_FIELD)
+                            .and_then(|topic| ic_cdk::export::candid::decode_one(&topic.value).ok())?;
+
+                        if name != #name_str {
+                            return None;
+                        }
+
+                        Some(Self::from_event(event))
+                    }
+                }
+            }
+        }
+
+        impl ic_event_hub::types::IEventSchema for #name {
+            fn event_schema() -> ic_event_hub::types::EventSchema {
+                ic_event_hub::types::EventSchema {
+                    name: String::from(#name_str),
+                    version: #version,
+                    topics: vec![#topics_schema],
+                    values: vec![#values_schema],
+                }
+            }
         }
 
         #[derive(Debug)]
@@ -168,3 +269,68 @@ pub fn event_macro_impl(input: TokenStream) -> TokenStream {
 
     gen.into()
 }
+
+/// Checks whether the attribute is `#[topic(key)]`
+fn is_key_topic_attr(attr: &Attribute) -> bool {
+    if !attr.path.is_ident("topic") {
+        return false;
+    }
+
+    match attr.parse_meta() {
+        Ok(Meta::List(list)) => list.nested.iter().any(|nested| match nested {
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("key") => true,
+            _ => panic!("Unknown topic attribute"),
+        }),
+        _ => false,
+    }
+}
+
+struct EventAttrs {
+    version: u32,
+    retain: bool,
+}
+
+/// Reads `#[event(version = N, retain)]`, defaulting to version `1` and no retention
+fn parse_event_attrs(attrs: &[Attribute]) -> EventAttrs {
+    let mut res = EventAttrs {
+        version: 1,
+        retain: false,
+    };
+
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("event")) {
+        let list = match attr.parse_meta() {
+            Ok(Meta::List(list)) => list,
+            _ => panic!("Expected #[event(version = N, retain)]"),
+        };
+
+        for nested in list.nested.iter() {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(name_value))
+                    if name_value.path.is_ident("version") =>
+                {
+                    res.version = match &name_value.lit {
+                        Lit::Int(lit) => lit.base10_parse().unwrap(),
+                        _ => panic!("Event version should be an integer"),
+                    }
+                }
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("retain") => {
+                    res.retain = true;
+                }
+                _ => panic!("Unknown event attribute"),
+            }
+        }
+    }
+
+    res
+}
+
+fn fields_schema(fields: &[(Ident, Type, String)]) -> proc_macro2::TokenStream {
+    fields.iter().fold(quote!(), |es, (_, field_type, field_name)| {
+        quote! {
+            #es ic_event_hub::types::EventFieldSchema {
+                name: String::from(#field_name),
+                candid_type: <#field_type as ic_cdk::export::candid::CandidType>::ty().to_string(),
+            },
+        }
+    })
+}
@@ -34,18 +34,41 @@ mod derive;
 
 /// Generates an implementation of `ic_event_hub::types::IEvent` trait for a given struct. Also generates a `*Filter`
 /// struct and an implementation of `ic_event_hub::types::IEventFilter` trait for that struct which can be used to filter
-/// topics while listening to the given event.
+/// topics while listening to the given event, and an implementation of `ic_event_hub::types::IEventSchema` trait which
+/// describes names and candid types of the event fields.
 ///
-/// Fields of the event struct have to implement `candid::CandidType` and `candid::Deserialize`
+/// Fields of the event struct have to implement `candid::CandidType` and `candid::Deserialize`.
+/// Topics are encoded with plain `encode_one()`, the same way older versions did, while the hub
+/// compares them with filters canonically (see `ic_event_hub::topic`), so they match filters built
+/// with other candid types of the same values, e.g. `nat` instead of `nat32`.
 ///
 /// Usage:
 /// ```
 /// #[derive(Event)]
+/// #[event(version = 2)]
 /// struct MyEvent {
 ///     ...
 /// }
 /// ```
-#[proc_macro_derive(Event, attributes(topic))]
+///
+/// The optional `#[event(version = N)]` attribute sets the version of the event schema, which is
+/// returned by the `get_event_catalog` query of the emitter (see `implement_event_catalog!()`).
+///
+/// A topic marked with `#[topic(key)]` is the compaction key of the event: the event log of the
+/// emitter only keeps the newest event (or tombstone) of the same type for each value of that
+/// field.
+///
+/// Events marked with `#[event(retain)]` are state-like: the emitter keeps the latest one per
+/// distinct set of topics and sends it to every new listener with a matching filter, so listeners
+/// receive the current value right after they subscribe.
+///
+/// The emitter adds the schema of a derived event type to its event catalog the first time it emits
+/// such an event, so the catalog always describes the events the emitter actually publishes.
+///
+/// The generated `IEvent` also encodes the event compactly for callbacks subscribed with
+/// `BatchFormat::Compact` - the values of all the fields in the order of their declaration, without
+/// field names - and decodes it back with `from_compact_event()`
+#[proc_macro_derive(Event, attributes(topic, event))]
 pub fn event_macro_derive(input: TokenStream) -> TokenStream {
     event_macro_impl(input)
 }
@@ -0,0 +1,776 @@
+//! In-process simulator of event-hub enabled canisters.
+//!
+//! Hosts several emitters and listeners in a single process, routes batches sent by emitters to
+//! listener closures, advances a virtual clock and injects call rejections and latency - so the
+//! whole subscribe -> emit -> receive flow could be tested with `cargo test`, without `dfx`.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use candid::{decode_args, decode_one, encode_args, CandidType, Deserialize};
+use futures::channel::oneshot;
+use futures::executor::LocalPool;
+use futures::task::LocalSpawnExt;
+use ic_cdk::api::call::{CallResult, RejectionCode};
+use ic_cdk::export::Principal;
+use ic_event_hub::event_hub::EventHub;
+use ic_event_hub::fns::{
+    commit_impl, emit_impl, send_events_async_impl, subscribe_impl, unsubscribe_impl,
+};
+use ic_event_hub::runtime::{RawCallFuture, Runtime};
+use ic_event_hub::transaction::EventTransaction;
+use ic_event_hub::types::{
+    BatchFormat, CompactEvent, CompressedEventBatch, EmitReceipt, Event, EventBatch, EventHubError,
+    IEvent, RemoteCallEndpoint, SendReport, SubscribeRequest, UnsubscribeRequest,
+};
+
+/// A simulated listener canister method, which receives the emitter id and the delivered events
+///
+/// Batches are decoded according to the format of the subscription, so the same closure works for
+/// every format except `BatchFormat::Compact` with events that have a compact form - use
+/// `RawListenerFn` for those
+pub type ListenerFn = Box<dyn FnMut(Principal, Vec<Event>)>;
+
+/// Same as `ListenerFn`, but also receives the sequence number of the batch, like the callbacks
+/// made with `implement_event_callback!(method, handler, ordered)` do
+pub type OrderedListenerFn = Box<dyn FnMut(Principal, Vec<Event>, u64)>;
+
+/// A simulated canister method, which receives the caller id and the candid encoded arguments as
+/// they are. An error makes the call rejected.
+pub type RawListenerFn = Box<dyn FnMut(Principal, Vec<u8>) -> Result<(), String>>;
+
+#[derive(Clone)]
+enum Listener {
+    Events(Rc<RefCell<ListenerFn>>),
+    Ordered(Rc<RefCell<OrderedListenerFn>>),
+    Raw(Rc<RefCell<RawListenerFn>>),
+}
+
+struct InFlightCall {
+    deliver_at: u64,
+    caller: Principal,
+    endpoint: RemoteCallEndpoint,
+    args: Vec<u8>,
+    // the batch format of the endpoint and whether the delivery is ordered, at the moment of the call
+    format: (BatchFormat, bool),
+    reply: oneshot::Sender<CallResult<Vec<u8>>>,
+}
+
+#[derive(Default)]
+struct SimulatorState {
+    time: u64,
+    latency_nano: u64,
+    emitters: Vec<SimulatedEmitter>,
+    listeners: HashMap<RemoteCallEndpoint, Listener>,
+    rejections: HashMap<RemoteCallEndpoint, (RejectionCode, String)>,
+    in_flight: Vec<InFlightCall>,
+    logs: Vec<String>,
+}
+
+/// Runtime of a simulated canister - calls made through it are routed by the simulator
+#[derive(Clone)]
+struct SimulatedRuntime {
+    state: Rc<RefCell<SimulatorState>>,
+    id: Principal,
+    caller: Rc<Cell<Principal>>,
+}
+
+impl Runtime for SimulatedRuntime {
+    fn time(&self) -> u64 {
+        self.state.borrow().time
+    }
+
+    fn caller(&self) -> Principal {
+        self.caller.get()
+    }
+
+    fn id(&self) -> Principal {
+        self.id
+    }
+
+    fn call_raw(&self, canister_id: Principal, method_name: &str, args: Vec<u8>) -> RawCallFuture {
+        let (reply, response) = oneshot::channel();
+
+        let endpoint = RemoteCallEndpoint {
+            canister_id,
+            method_name: String::from(method_name),
+        };
+
+        let mut state = self.state.borrow_mut();
+        let deliver_at = state.time + state.latency_nano;
+
+        // the format this batch was encoded with, it may change before the call is delivered
+        let format = state
+            .emitters
+            .iter()
+            .find(|emitter| emitter.id() == self.id)
+            .map(|emitter| {
+                emitter.with_event_hub(|hub| {
+                    (hub.get_batch_format(&endpoint), hub.is_ordered_delivery())
+                })
+            })
+            .unwrap_or_default();
+
+        state.in_flight.push(InFlightCall {
+            deliver_at,
+            caller: self.id,
+            endpoint,
+            args,
+            format,
+            reply,
+        });
+
+        Box::pin(async move {
+            response.await.unwrap_or_else(|_| {
+                Err((
+                    RejectionCode::SysTransient,
+                    String::from("The call was dropped"),
+                ))
+            })
+        })
+    }
+
+    fn log(&self, message: String) {
+        self.state.borrow_mut().logs.push(message);
+    }
+}
+
+/// An event-hub enabled canister hosted by the simulator
+#[derive(Clone)]
+pub struct SimulatedEmitter {
+    hub: Rc<RefCell<EventHub>>,
+    runtime: SimulatedRuntime,
+    reports: Rc<RefCell<Vec<SendReport>>>,
+}
+
+impl SimulatedEmitter {
+    pub fn id(&self) -> Principal {
+        self.runtime.id
+    }
+
+    pub fn with_event_hub<R>(&self, f: impl FnOnce(&mut EventHub) -> R) -> R {
+        f(&mut self.hub.borrow_mut())
+    }
+
+    pub fn emit(&self, event: impl IEvent) -> Result<EmitReceipt, EventHubError> {
+        emit_impl(event, &mut self.hub.borrow_mut(), &self.runtime)
+    }
+
+    pub fn commit_events(
+        &self,
+        transaction: EventTransaction,
+    ) -> Result<Vec<EmitReceipt>, EventHubError> {
+        commit_impl(transaction, &mut self.hub.borrow_mut(), &self.runtime)
+    }
+
+    /// Subscribes as if `listener` called the `subscribe()` method of this canister
+    pub fn subscribe(&self, listener: Principal, request: SubscribeRequest) {
+        self.runtime.caller.set(listener);
+        subscribe_impl(request, &mut self.hub.borrow_mut(), &self.runtime);
+    }
+
+    /// Unsubscribes as if `listener` called the `unsubscribe()` method of this canister
+    pub fn unsubscribe(&self, listener: Principal, request: UnsubscribeRequest) {
+        self.runtime.caller.set(listener);
+        unsubscribe_impl(request, &mut self.hub.borrow_mut(), &self.runtime);
+    }
+
+    /// Returns reports of all the deliveries finished since the previous invocation
+    pub fn take_reports(&self) -> Vec<SendReport> {
+        self.reports.take()
+    }
+}
+
+/// A set of simulated canisters sharing the same virtual clock
+///
+/// Emitters send their batches whenever the clock reaches a batch deadline. Each call is delivered
+/// `latency_nano` after it was made, unless its endpoint is rejected with `reject_calls_to()`.
+pub struct Simulator {
+    state: Rc<RefCell<SimulatorState>>,
+    pool: RefCell<LocalPool>,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        Self {
+            state: Rc::new(RefCell::new(SimulatorState::default())),
+            pool: RefCell::new(LocalPool::new()),
+        }
+    }
+
+    pub fn add_emitter(&self, id: Principal, hub: EventHub) -> SimulatedEmitter {
+        let emitter = SimulatedEmitter {
+            hub: Rc::new(RefCell::new(hub)),
+            runtime: SimulatedRuntime {
+                state: self.state.clone(),
+                id,
+                caller: Rc::new(Cell::new(Principal::anonymous())),
+            },
+            reports: Rc::new(RefCell::new(vec![])),
+        };
+
+        self.state.borrow_mut().emitters.push(emitter.clone());
+
+        emitter
+    }
+
+    pub fn add_listener(
+        &self,
+        canister_id: Principal,
+        method_name: &str,
+        listener: impl FnMut(Principal, Vec<Event>) + 'static,
+    ) {
+        let endpoint = RemoteCallEndpoint {
+            canister_id,
+            method_name: String::from(method_name),
+        };
+
+        self.state.borrow_mut().listeners.insert(
+            endpoint,
+            Listener::Events(Rc::new(RefCell::new(Box::new(listener)))),
+        );
+    }
+
+    /// Same as `add_listener()`, but the method also receives sequence numbers of batches, which
+    /// are only sent by emitters with the ordered delivery enabled
+    pub fn add_ordered_listener(
+        &self,
+        canister_id: Principal,
+        method_name: &str,
+        listener: impl FnMut(Principal, Vec<Event>, u64) + 'static,
+    ) {
+        let endpoint = RemoteCallEndpoint {
+            canister_id,
+            method_name: String::from(method_name),
+        };
+
+        self.state.borrow_mut().listeners.insert(
+            endpoint,
+            Listener::Ordered(Rc::new(RefCell::new(Box::new(listener)))),
+        );
+    }
+
+    /// Same as `add_listener()`, but the method receives candid encoded arguments of the call
+    pub fn add_raw_listener(
+        &self,
+        canister_id: Principal,
+        method_name: &str,
+        listener: impl FnMut(Principal, Vec<u8>) -> Result<(), String> + 'static,
+    ) {
+        let endpoint = RemoteCallEndpoint {
+            canister_id,
+            method_name: String::from(method_name),
+        };
+
+        self.state.borrow_mut().listeners.insert(
+            endpoint,
+            Listener::Raw(Rc::new(RefCell::new(Box::new(listener)))),
+        );
+    }
+
+    pub fn time(&self) -> u64 {
+        self.state.borrow().time
+    }
+
+    pub fn set_latency(&self, latency_nano: u64) {
+        self.state.borrow_mut().latency_nano = latency_nano;
+    }
+
+    pub fn reject_calls_to(
+        &self,
+        endpoint: RemoteCallEndpoint,
+        code: RejectionCode,
+        message: &str,
+    ) {
+        self.state
+            .borrow_mut()
+            .rejections
+            .insert(endpoint, (code, String::from(message)));
+    }
+
+    pub fn accept_calls_to(&self, endpoint: &RemoteCallEndpoint) {
+        self.state.borrow_mut().rejections.remove(endpoint);
+    }
+
+    pub fn get_logs(&self) -> Vec<String> {
+        self.state.borrow().logs.clone()
+    }
+
+    /// Moves the clock forward, stopping at every batch deadline and call delivery on the way
+    pub fn advance_time(&self, delta_nano: u64) {
+        let target = self.time() + delta_nano;
+
+        loop {
+            self.run();
+
+            match self.next_deadline() {
+                Some(deadline) if deadline <= target => self.state.borrow_mut().time = deadline,
+                _ => break,
+            }
+        }
+
+        self.state.borrow_mut().time = target;
+        self.run();
+    }
+
+    /// Sends all the batches which are ready by now and delivers all the calls which are due
+    pub fn run(&self) {
+        let emitters = self.state.borrow().emitters.clone();
+        let spawner = self.pool.borrow().spawner();
+
+        for emitter in emitters {
+            spawner
+                .spawn_local(async move {
+                    let report = send_events_async_impl(&*emitter.hub, &emitter.runtime).await;
+
+                    if report.delivered_batches > 0 || !report.failed.is_empty() {
+                        emitter.reports.borrow_mut().push(report);
+                    }
+                })
+                .expect("Unable to spawn a delivery");
+        }
+
+        loop {
+            self.pool.borrow_mut().run_until_stalled();
+
+            if !self.deliver_due_calls() {
+                break;
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Option<u64> {
+        let state = self.state.borrow();
+        let now = state.time;
+
+        let batch_deadlines = state
+            .emitters
+            .iter()
+            .filter_map(|emitter| emitter.hub.borrow().next_batch_deadline());
+        let call_deadlines = state.in_flight.iter().map(|call| call.deliver_at);
+
+        batch_deadlines
+            .chain(call_deadlines)
+            .filter(|deadline| *deadline > now)
+            .min()
+    }
+
+    fn deliver_due_calls(&self) -> bool {
+        let due = {
+            let mut state = self.state.borrow_mut();
+            let now = state.time;
+
+            let (due, in_flight) = state
+                .in_flight
+                .drain(..)
+                .partition::<Vec<_>, _>(|call| call.deliver_at <= now);
+            state.in_flight = in_flight;
+
+            due
+        };
+
+        let delivered_any = !due.is_empty();
+
+        for call in due {
+            let (rejection, listener) = {
+                let state = self.state.borrow();
+
+                (
+                    state.rejections.get(&call.endpoint).cloned(),
+                    state.listeners.get(&call.endpoint).cloned(),
+                )
+            };
+
+            let result = match (rejection, listener) {
+                (Some(rejection), _) => Err(rejection),
+                (None, None) => Err((
+                    RejectionCode::DestinationInvalid,
+                    format!(
+                        "No method {} in canister {}",
+                        call.endpoint.method_name, call.endpoint.canister_id
+                    ),
+                )),
+                (None, Some(listener)) => {
+                    let (format, ordered) = call.format;
+
+                    match listener {
+                        Listener::Events(listener) => decode_events(&call.args, format, ordered)
+                            .map(|events| {
+                                let mut listener = listener.borrow_mut();
+                                (*listener)(call.caller, events);
+                            }),
+                        Listener::Ordered(listener) => decode_bare_events(&call.args, format, true)
+                            .map(|(events, seq)| {
+                                let mut listener = listener.borrow_mut();
+                                (*listener)(call.caller, events, seq.unwrap());
+                            })
+                            .map_err(|e| format!("Unable to decode an event batch - {}", e)),
+                        Listener::Raw(listener) => {
+                            let mut listener = listener.borrow_mut();
+                            (*listener)(call.caller, call.args)
+                        }
+                    }
+                    .map(|_| encode_args(()).expect("Unable to encode reply"))
+                    .map_err(|e| (RejectionCode::CanisterError, e))
+                }
+            };
+
+            // the emitter could be gone already, which is fine
+            let _ = call.reply.send(result);
+        }
+
+        delivered_any
+    }
+}
+
+/// Decodes a batch the same way `implement_event_callback!()` does for the format
+fn decode_events(args: &[u8], format: BatchFormat, ordered: bool) -> Result<Vec<Event>, String> {
+    let decoded = match format {
+        BatchFormat::Envelope => decode_one::<EventBatch>(args)
+            .map(|batch| batch.events)
+            .map_err(|e| e.to_string()),
+        BatchFormat::Compressed => decode_one::<CompressedEventBatch>(args)
+            .map_err(|e| e.to_string())
+            .and_then(|batch| batch.decompress()),
+        BatchFormat::Events | BatchFormat::Compact => {
+            decode_bare_events(args, format, ordered).map(|(events, _)| events)
+        }
+    };
+
+    decoded.map_err(|e| format!("Unable to decode an event batch - {}", e))
+}
+
+/// Decodes a bare `Vec<Event>` batch, or a `Vec<CompactEvent>` one for `BatchFormat::Compact`,
+/// followed by its sequence number if `ordered` is set
+fn decode_bare_events(
+    args: &[u8],
+    format: BatchFormat,
+    ordered: bool,
+) -> Result<(Vec<Event>, Option<u64>), String> {
+    if format != BatchFormat::Compact {
+        return decode_bare::<Event>(args, ordered);
+    }
+
+    let (events, seq) = decode_bare::<CompactEvent>(args, ordered)?;
+    let events = events
+        .into_iter()
+        .map(|event| match event {
+            CompactEvent::Full(event) => Ok(event),
+            CompactEvent::Fields { name, .. } => Err(format!(
+                "Compact event {} can only be received by a raw listener",
+                name
+            )),
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok((events, seq))
+}
+
+fn decode_bare<T: CandidType + for<'de> Deserialize<'de>>(
+    args: &[u8],
+    ordered: bool,
+) -> Result<(Vec<T>, Option<u64>), String> {
+    if ordered {
+        decode_args::<(Vec<T>, u64)>(args)
+            .map(|(events, seq)| (events, Some(seq)))
+            .map_err(|e| e.to_string())
+    } else {
+        decode_one(args)
+            .map(|events| (events, None))
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{SimulatedEmitter, Simulator};
+    use candid::{decode_one, encode_one, Principal};
+    use ic_cdk::api::call::RejectionCode;
+    use ic_event_hub::event_hub::EventHub;
+    use ic_event_hub::types::{
+        BatchFormat, CallbackInfo, CompactEvent, Event, EventFilter, IEvent, RemoteCallEndpoint,
+        SubscribeRequest,
+    };
+    use std::cell::RefCell;
+    use std::collections::BTreeSet;
+    use std::rc::Rc;
+
+    struct TestEvent;
+
+    impl IEvent for TestEvent {
+        fn to_event(&self) -> Event {
+            Event {
+                topics: BTreeSet::new(),
+                values: vec![],
+            }
+        }
+
+        fn from_event(_: Event) -> Self {
+            TestEvent
+        }
+    }
+
+    struct CompactTestEvent(u32);
+
+    impl IEvent for CompactTestEvent {
+        fn to_event(&self) -> Event {
+            Event {
+                topics: BTreeSet::new(),
+                values: vec![],
+            }
+        }
+
+        fn from_event(_: Event) -> Self {
+            unreachable!()
+        }
+
+        fn to_compact_event(&self) -> CompactEvent {
+            CompactEvent::Fields {
+                name: String::from("CompactTestEvent"),
+                values: encode_one(self.0).unwrap(),
+            }
+        }
+
+        fn from_compact_event(event: CompactEvent) -> Option<Self> {
+            match event {
+                CompactEvent::Fields { values, .. } => {
+                    Some(CompactTestEvent(decode_one(&values).unwrap()))
+                }
+                CompactEvent::Full(_) => None,
+            }
+        }
+    }
+
+    fn subscribe(
+        emitter: &SimulatedEmitter,
+        canister_id: Principal,
+        method_name: &str,
+        format: BatchFormat,
+    ) {
+        emitter.subscribe(
+            canister_id,
+            SubscribeRequest {
+                callbacks: vec![CallbackInfo {
+                    filter: EventFilter::empty(),
+                    method_name: String::from(method_name),
+                    format: Some(format),
+                }],
+            },
+        );
+    }
+
+    #[test]
+    fn subscribe_emit_receive_works_fine() {
+        let sim = Simulator::new();
+        sim.set_latency(5);
+
+        let mut hub = EventHub::new(10, 1024);
+        hub.set_max_delivery_attempts(2);
+        let emitter = sim.add_emitter(Principal::from_slice(&[1]), hub);
+
+        let listener = RemoteCallEndpoint {
+            canister_id: Principal::from_slice(&[2]),
+            method_name: String::from("events_callback"),
+        };
+        let received = Rc::new(RefCell::new(vec![]));
+        let received_clone = received.clone();
+        sim.add_listener(
+            listener.canister_id,
+            listener.method_name.as_str(),
+            move |emitter_id, events| received_clone.borrow_mut().push((emitter_id, events.len())),
+        );
+
+        emitter.subscribe(
+            listener.canister_id,
+            SubscribeRequest {
+                callbacks: vec![CallbackInfo {
+                    filter: EventFilter::empty(),
+                    method_name: listener.method_name.clone(),
+                    format: None,
+                }],
+            },
+        );
+
+        emitter.emit(TestEvent).unwrap();
+        emitter.emit(TestEvent).unwrap();
+
+        // the batch is sent at 10 and is delivered at 15
+        sim.advance_time(12);
+        assert!(received.borrow().is_empty());
+
+        sim.advance_time(3);
+        assert_eq!(received.borrow().as_slice(), &[(emitter.id(), 2)]);
+        assert_eq!(emitter.take_reports()[0].delivered_events, 2);
+
+        sim.reject_calls_to(listener.clone(), RejectionCode::CanisterError, "Oops");
+        emitter.emit(TestEvent).unwrap();
+        sim.advance_time(15);
+
+        let reports = emitter.take_reports();
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].failed[0].will_retry);
+
+        sim.accept_calls_to(&listener);
+        sim.advance_time(5);
+        assert_eq!(received.borrow().len(), 2);
+    }
+
+    #[test]
+    fn batches_are_decoded_according_to_format() {
+        let sim = Simulator::new();
+        let emitter = sim.add_emitter(Principal::from_slice(&[1]), EventHub::new(10, 1024));
+        let listener_id = Principal::from_slice(&[2]);
+
+        let received = Rc::new(RefCell::new(vec![]));
+
+        for (method_name, format) in [
+            ("events_callback", BatchFormat::Events),
+            ("envelope_callback", BatchFormat::Envelope),
+            ("compressed_callback", BatchFormat::Compressed),
+            ("compact_callback", BatchFormat::Compact),
+        ] {
+            let received = received.clone();
+            sim.add_listener(listener_id, method_name, move |_, events| {
+                received.borrow_mut().push((method_name, events.len()))
+            });
+
+            subscribe(&emitter, listener_id, method_name, format);
+        }
+
+        emitter.emit(TestEvent).unwrap();
+        emitter.emit(TestEvent).unwrap();
+        sim.advance_time(10);
+
+        let mut received = received.take();
+        received.sort_unstable();
+
+        assert_eq!(
+            received,
+            vec![
+                ("compact_callback", 2),
+                ("compressed_callback", 2),
+                ("envelope_callback", 2),
+                ("events_callback", 2)
+            ]
+        );
+        assert!(emitter.take_reports()[0].failed.is_empty());
+    }
+
+    #[test]
+    fn ordered_batches_carry_sequence_numbers() {
+        let sim = Simulator::new();
+
+        let mut hub = EventHub::new(10, 1024);
+        hub.set_ordered_delivery(true);
+        let emitter = sim.add_emitter(Principal::from_slice(&[1]), hub);
+        let listener_id = Principal::from_slice(&[2]);
+
+        let seqs = Rc::new(RefCell::new(vec![]));
+        let seqs_clone = seqs.clone();
+        sim.add_ordered_listener(listener_id, "ordered_callback", move |_, events, seq| {
+            seqs_clone.borrow_mut().push((events.len(), seq))
+        });
+
+        let received = Rc::new(RefCell::new(0));
+        let received_clone = received.clone();
+        sim.add_listener(listener_id, "events_callback", move |_, events| {
+            *received_clone.borrow_mut() += events.len()
+        });
+
+        subscribe(
+            &emitter,
+            listener_id,
+            "ordered_callback",
+            BatchFormat::Events,
+        );
+        subscribe(
+            &emitter,
+            listener_id,
+            "events_callback",
+            BatchFormat::Events,
+        );
+
+        emitter.emit(TestEvent).unwrap();
+        emitter.emit(TestEvent).unwrap();
+        sim.advance_time(10);
+
+        emitter.emit(TestEvent).unwrap();
+        sim.advance_time(10);
+
+        assert_eq!(seqs.borrow().as_slice(), &[(2, 0), (1, 1)]);
+        assert_eq!(*received.borrow(), 3);
+
+        // a lost batch shows up as a gap in sequence numbers
+        let ordered_endpoint = RemoteCallEndpoint {
+            canister_id: listener_id,
+            method_name: String::from("ordered_callback"),
+        };
+        sim.reject_calls_to(
+            ordered_endpoint.clone(),
+            RejectionCode::CanisterError,
+            "Oops",
+        );
+        emitter.emit(TestEvent).unwrap();
+        sim.advance_time(10);
+
+        sim.accept_calls_to(&ordered_endpoint);
+        emitter.emit(TestEvent).unwrap();
+        sim.advance_time(10);
+
+        assert_eq!(seqs.borrow().as_slice(), &[(2, 0), (1, 1), (1, 3)]);
+    }
+
+    #[test]
+    fn compact_events_are_received_by_raw_listeners_only() {
+        let sim = Simulator::new();
+        let emitter = sim.add_emitter(Principal::from_slice(&[1]), EventHub::new(10, 1024));
+        let listener_id = Principal::from_slice(&[2]);
+
+        let received = Rc::new(RefCell::new(vec![]));
+        let received_clone = received.clone();
+        sim.add_raw_listener(listener_id, "raw_callback", move |_, args| {
+            let events: Vec<CompactEvent> = decode_one(&args).map_err(|e| e.to_string())?;
+
+            for event in events {
+                let event = CompactTestEvent::from_compact_event(event)
+                    .ok_or_else(|| String::from("Unexpected event"))?;
+                received_clone.borrow_mut().push(event.0);
+            }
+
+            Ok(())
+        });
+        sim.add_listener(listener_id, "compact_callback", |_, _| {
+            panic!("Compact events should not be decoded as full ones")
+        });
+
+        subscribe(&emitter, listener_id, "raw_callback", BatchFormat::Compact);
+        subscribe(
+            &emitter,
+            listener_id,
+            "compact_callback",
+            BatchFormat::Compact,
+        );
+
+        emitter.emit(CompactTestEvent(42)).unwrap();
+        sim.advance_time(10);
+
+        assert_eq!(received.borrow().as_slice(), &[42]);
+
+        // the batch which can't be decoded is rejected instead of crashing the simulator
+        let report = &emitter.take_reports()[0];
+        assert_eq!(report.delivered_batches, 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].endpoint.method_name, "compact_callback");
+        assert_eq!(
+            report.failed[0].rejection_code,
+            RejectionCode::CanisterError
+        );
+    }
+}
@@ -1,10 +1,17 @@
 use async_trait::async_trait;
-use ic_cdk::api::call::CallResult;
-use ic_cdk::call;
+use candid::types::internal::find_type;
+use candid::types::{Field, Type};
+use candid::{check_prog, decode_one, encode_one, CandidType, IDLProg, TypeEnv};
+use ic_cdk::api::call::{call_raw, CallResult};
+use ic_cdk::api::time;
 use ic_cdk::export::candid::Principal;
+use ic_cdk::{call, id, print};
 
+use crate::subscription_registry::with_subscription_registry;
 use crate::types::{
-    GetSubscribersRequest, GetSubscribersResponse, SubscribeRequest, UnsubscribeRequest,
+    BatchFormat, CallbackInfo, CompactEvent, CompressedEventBatch, Event, EventBatch, EventSchema,
+    GetSubscribersRequest, GetSubscribersResponse, IEventFilter, RemoteCallEndpoint,
+    SubscribeRequest, SubscribeResponse, UnsubscribeRequest,
 };
 
 #[async_trait]
@@ -15,16 +22,76 @@ pub trait IEventHubClient {
         &self,
         request: GetSubscribersRequest,
     ) -> CallResult<(GetSubscribersResponse,)>;
+    async fn subscription_epoch(&self) -> CallResult<(u64,)>;
+    async fn get_event_catalog(&self) -> CallResult<(Vec<EventSchema>,)>;
+
+    /// Subscribes `method_name` of this canister to events matching the typed `filter`
+    async fn subscribe_to<F: IEventFilter + Send>(
+        &self,
+        filter: F,
+        method_name: &str,
+    ) -> CallResult<()>;
+
+    /// Removes a subscription previously made with `subscribe_to()`
+    async fn unsubscribe_from<F: IEventFilter + Send>(
+        &self,
+        filter: F,
+        method_name: &str,
+    ) -> CallResult<()>;
 }
 
 #[async_trait]
 impl IEventHubClient for Principal {
     async fn subscribe(&self, req: SubscribeRequest) -> CallResult<()> {
-        call(*self, "subscribe", (req,)).await
+        // callbacks are registered before the call, because the emitter may send a batch before
+        // the response arrives; only the ones this call added are removed if it fails
+        let added: Vec<_> = with_subscription_registry(|registry| {
+            let added = req
+                .callbacks
+                .iter()
+                .filter(|callback| {
+                    registry
+                        .get_callbacks(self)
+                        .map(|callbacks| !callbacks.contains(callback))
+                        .unwrap_or(true)
+                })
+                .cloned()
+                .collect();
+
+            registry.add_callbacks(*self, req.callbacks.clone());
+
+            added
+        });
+
+        let args = encode_one(&req).expect("Unable to encode a subscribe request");
+
+        match call_raw(*self, "subscribe", args, 0).await {
+            Ok(reply) => {
+                // batches of a listener served by a relay are sent by the relay
+                let relays = decode_one::<SubscribeResponse>(&reply)
+                    .map(|it| it.relays)
+                    .unwrap_or_default();
+                with_subscription_registry(|registry| registry.set_relays(*self, relays));
+
+                Ok(())
+            }
+            Err(e) => {
+                with_subscription_registry(|registry| registry.remove_callbacks(self, &added));
+
+                Err(e)
+            }
+        }
     }
 
     async fn unsubscribe(&self, req: UnsubscribeRequest) -> CallResult<()> {
-        call(*self, "unsubscribe", (req,)).await
+        let callbacks = req.callbacks.clone();
+        let res: CallResult<()> = call(*self, "unsubscribe", (req,)).await;
+
+        if res.is_ok() {
+            with_subscription_registry(|registry| registry.remove_callbacks(self, &callbacks));
+        }
+
+        res
     }
 
     async fn get_subscribers(
@@ -33,4 +100,370 @@ impl IEventHubClient for Principal {
     ) -> CallResult<(GetSubscribersResponse,)> {
         call(*self, "get_subscribers", (req,)).await
     }
+
+    async fn subscription_epoch(&self) -> CallResult<(u64,)> {
+        call(*self, "subscription_epoch", ()).await
+    }
+
+    async fn get_event_catalog(&self) -> CallResult<(Vec<EventSchema>,)> {
+        call(*self, "get_event_catalog", ()).await
+    }
+
+    async fn subscribe_to<F: IEventFilter + Send>(
+        &self,
+        filter: F,
+        method_name: &str,
+    ) -> CallResult<()> {
+        let req = SubscribeRequestBuilder::new()
+            .callback(&filter, method_name)
+            .build();
+
+        self.subscribe(req).await
+    }
+
+    async fn unsubscribe_from<F: IEventFilter + Send>(
+        &self,
+        filter: F,
+        method_name: &str,
+    ) -> CallResult<()> {
+        let req = SubscribeRequestBuilder::new()
+            .callback(&filter, method_name)
+            .build();
+
+        self.unsubscribe(req).await
+    }
+}
+
+/// Checks that emitters this canister is subscribed to still know about its subscriptions and
+/// subscribes again to those which were lost (e.g. when an emitter was reinstalled)
+///
+/// Does nothing if less than `interval_nano` passed since the previous verification, so it is safe
+/// to call it from a heartbeat. Returns emitters which were subscribed to again.
+pub async fn verify_subscriptions(interval_nano: u64) -> Vec<Principal> {
+    let now = time();
+    let emitters = with_subscription_registry(|registry| {
+        if registry.last_verified_at + interval_nano > now {
+            return vec![];
+        }
+
+        registry.last_verified_at = now;
+        registry.get_emitters()
+    });
+
+    let mut resubscribed = vec![];
+
+    for emitter in emitters {
+        match verify_emitter_subscriptions(emitter).await {
+            Ok(true) => resubscribed.push(emitter),
+            Ok(false) => {}
+            Err((code, msg)) => print(format!(
+                "[Canister {}] - unable to verify subscriptions to {}: {:?} {}",
+                id(),
+                emitter,
+                code,
+                msg
+            )),
+        }
+    }
+
+    resubscribed
+}
+
+async fn verify_emitter_subscriptions(emitter: Principal) -> CallResult<bool> {
+    let (epoch,) = emitter.subscription_epoch().await?;
+
+    let known_epoch = with_subscription_registry(|registry| registry.get_epoch(&emitter));
+    if known_epoch == Some(epoch) {
+        return Ok(false);
+    }
+
+    let callbacks: Vec<CallbackInfo> =
+        match with_subscription_registry(|registry| registry.get_callbacks(&emitter).cloned()) {
+            Some(callbacks) => callbacks.into_iter().collect(),
+            None => return Ok(false),
+        };
+
+    let (response,) = emitter
+        .get_subscribers(GetSubscribersRequest {
+            filters: callbacks.iter().map(|it| it.filter.clone()).collect(),
+        })
+        .await?;
+
+    let missing: Vec<CallbackInfo> = callbacks
+        .into_iter()
+        .zip(response.subscribers.into_iter())
+        .filter(|(callback, subscribers)| {
+            let endpoint = RemoteCallEndpoint {
+                canister_id: id(),
+                method_name: callback.method_name.clone(),
+            };
+
+            !subscribers.contains(&endpoint)
+        })
+        .map(|(callback, _)| callback)
+        .collect();
+
+    if missing.is_empty() {
+        with_subscription_registry(|registry| registry.set_epoch(emitter, epoch));
+
+        return Ok(false);
+    }
+
+    emitter
+        .subscribe(SubscribeRequest { callbacks: missing })
+        .await?;
+
+    Ok(true)
+}
+
+/// Builds a `SubscribeRequest` (or an `UnsubscribeRequest`) out of typed event filters
+///
+/// Usage:
+/// ```ignore
+/// let req = SubscribeRequestBuilder::new()
+///     .callback(&MirrorEventFilter {}, "events_callback")
+///     .build_checked(&__export_service())?;
+/// ```
+#[derive(Default)]
+pub struct SubscribeRequestBuilder {
+    callbacks: Vec<CallbackInfo>,
+}
+
+impl SubscribeRequestBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn callback(mut self, filter: &impl IEventFilter, method_name: &str) -> Self {
+        self.callbacks.push(CallbackInfo {
+            filter: filter.to_event_filter(),
+            method_name: String::from(method_name),
+            format: None,
+        });
+
+        self
+    }
+
+    /// Same as `callback()`, but the method will receive batches as `EventBatch` envelopes
+    pub fn envelope_callback(mut self, filter: &impl IEventFilter, method_name: &str) -> Self {
+        self.callbacks.push(CallbackInfo {
+            filter: filter.to_event_filter(),
+            method_name: String::from(method_name),
+            format: Some(BatchFormat::Envelope),
+        });
+
+        self
+    }
+
+    /// Same as `callback()`, but the method will receive batches as `CompressedEventBatch`es
+    pub fn compressed_callback(mut self, filter: &impl IEventFilter, method_name: &str) -> Self {
+        self.callbacks.push(CallbackInfo {
+            filter: filter.to_event_filter(),
+            method_name: String::from(method_name),
+            format: Some(BatchFormat::Compressed),
+        });
+
+        self
+    }
+
+    /// Same as `callback()`, but the method will receive batches as `Vec<CompactEvent>`, which
+    /// carry field values without their names
+    pub fn compact_callback(mut self, filter: &impl IEventFilter, method_name: &str) -> Self {
+        self.callbacks.push(CallbackInfo {
+            filter: filter.to_event_filter(),
+            method_name: String::from(method_name),
+            format: Some(BatchFormat::Compact),
+        });
+
+        self
+    }
+
+    pub fn build(self) -> SubscribeRequest {
+        SubscribeRequest {
+            callbacks: self.callbacks,
+        }
+    }
+
+    /// Same as `build()`, but also checks that each callback method is present in the candid
+    /// interface of this canister (e.g. the one returned by `export_service!()`) and accepts
+    /// batches in the format of its subscription (followed by a `nat64` sequence number for
+    /// ordered `Events` and `Compact` callbacks)
+    pub fn build_checked(self, candid_interface: &str) -> Result<SubscribeRequest, String> {
+        check_callback_methods(candid_interface, &self.callbacks)?;
+
+        Ok(self.build())
+    }
+}
+
+fn check_callback_methods(
+    candid_interface: &str,
+    callbacks: &[CallbackInfo],
+) -> Result<(), String> {
+    let prog: IDLProg = candid_interface
+        .parse()
+        .map_err(|e| format!("Unable to parse candid interface - {}", e))?;
+
+    let mut env = TypeEnv::new();
+    let actor = check_prog(&mut env, &prog)
+        .map_err(|e| format!("Invalid candid interface - {}", e))?
+        .ok_or_else(|| String::from("Candid interface has no service"))?;
+
+    let methods = env
+        .as_service(&actor)
+        .map_err(|e| format!("Invalid candid service - {}", e))?;
+
+    for callback in callbacks {
+        let (_, method) = methods
+            .iter()
+            .find(|(name, _)| *name == callback.method_name)
+            .ok_or_else(|| {
+                format!(
+                    "No such method in candid interface: {}",
+                    callback.method_name
+                )
+            })?;
+
+        let func = env
+            .as_func(method)
+            .map_err(|e| format!("Invalid method {} - {}", callback.method_name, e))?;
+
+        let format = callback.format.unwrap_or_default();
+        let seq_allowed = matches!(format, BatchFormat::Events | BatchFormat::Compact);
+
+        let args_match = match func.args.as_slice() {
+            [batch] => types_match(&env, batch, &batch_type(format)),
+            [batch, seq] if seq_allowed => {
+                types_match(&env, batch, &batch_type(format)) && types_match(&env, seq, &u64::ty())
+            }
+            _ => false,
+        };
+
+        if !args_match {
+            return Err(format!(
+                "Method {} should accept a batch of type {} ({:?} format)",
+                callback.method_name,
+                batch_type(format),
+                format
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn batch_type(format: BatchFormat) -> Type {
+    match format {
+        BatchFormat::Events => Vec::<Event>::ty(),
+        BatchFormat::Envelope => EventBatch::ty(),
+        BatchFormat::Compressed => CompressedEventBatch::ty(),
+        BatchFormat::Compact => Vec::<CompactEvent>::ty(),
+    }
+}
+
+/// Structurally compares a type from a parsed candid interface with a type derived from rust
+fn types_match(env: &TypeEnv, actual: &Type, expected: &Type) -> bool {
+    let actual = match env.trace_type(actual) {
+        Ok(ty) => ty,
+        Err(_) => return false,
+    };
+    let expected = match expected {
+        Type::Knot(id) => match find_type(id) {
+            Some(ty) => ty,
+            None => return false,
+        },
+        ty => ty.clone(),
+    };
+
+    match (&actual, &expected) {
+        (Type::Opt(a), Type::Opt(e)) | (Type::Vec(a), Type::Vec(e)) => types_match(env, a, e),
+        (Type::Record(a), Type::Record(e)) | (Type::Variant(a), Type::Variant(e)) => {
+            fields_match(env, a, e)
+        }
+        (a, e) => a == e,
+    }
+}
+
+fn fields_match(env: &TypeEnv, actual: &[Field], expected: &[Field]) -> bool {
+    if actual.len() != expected.len() {
+        return false;
+    }
+
+    let mut actual: Vec<_> = actual.iter().collect();
+    let mut expected: Vec<_> = expected.iter().collect();
+    actual.sort_by_key(|field| field.id.get_id());
+    expected.sort_by_key(|field| field.id.get_id());
+
+    actual
+        .iter()
+        .zip(expected.iter())
+        .all(|(a, e)| a.id.get_id() == e.id.get_id() && types_match(env, &a.ty, &e.ty))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::SubscribeRequestBuilder;
+    use crate::types::{EventFilter, IEventFilter};
+
+    struct AnyEventFilter;
+
+    impl IEventFilter for AnyEventFilter {
+        fn to_event_filter(&self) -> EventFilter {
+            EventFilter::empty()
+        }
+
+        fn from_event_filter(_: EventFilter) -> Self {
+            AnyEventFilter
+        }
+    }
+
+    #[test]
+    fn callback_methods_are_checked() {
+        let did = r#"
+            type Event = record { topics : vec EventField; values : vec EventField };
+            type EventField = record { name : text; value : vec nat8 };
+            service : (principal) -> {
+                "events_callback" : (vec Event) -> ();
+                "ordered_callback" : (vec Event, nat64) -> ();
+                "text_callback" : (text) -> ();
+                "get_events_received" : () -> (nat64) query;
+            }
+        "#;
+
+        let req = SubscribeRequestBuilder::new()
+            .callback(&AnyEventFilter, "events_callback")
+            .build_checked(did)
+            .expect("Callback should be valid");
+        assert_eq!(req.callbacks.len(), 1);
+
+        let res = SubscribeRequestBuilder::new()
+            .callback(&AnyEventFilter, "get_events_received")
+            .build_checked(did);
+        assert!(res.is_err(), "Method without arguments is not a callback");
+
+        let res = SubscribeRequestBuilder::new()
+            .callback(&AnyEventFilter, "unknown_callback")
+            .build_checked(did);
+        assert!(res.is_err(), "Unknown method is not a callback");
+
+        let res = SubscribeRequestBuilder::new()
+            .callback(&AnyEventFilter, "text_callback")
+            .build_checked(did);
+        assert!(
+            res.is_err(),
+            "Method with a wrong argument type is not a callback"
+        );
+
+        let res = SubscribeRequestBuilder::new()
+            .envelope_callback(&AnyEventFilter, "events_callback")
+            .build_checked(did);
+        assert!(
+            res.is_err(),
+            "Envelope callback should accept an EventBatch"
+        );
+
+        let res = SubscribeRequestBuilder::new()
+            .callback(&AnyEventFilter, "ordered_callback")
+            .build_checked(did);
+        assert!(res.is_ok(), "Ordered callback is a valid callback");
+    }
 }
@@ -0,0 +1,151 @@
+use candid::types::Type;
+use candid::CandidType;
+
+use crate::types::{
+    BatchFormat, CompactEvent, CompressedEventBatch, Event, EventBatch, EventSchema,
+    GetSubscribersRequest, GetSubscribersResponse, SubscribeRequest, SubscribeResponse,
+    UnsubscribeRequest,
+};
+
+/// Returns candid declarations of the methods generated by `implement_subscribe!()`,
+/// `implement_unsubscribe!()`, `implement_get_subscribers!()` and `implement_subscription_epoch!()`
+pub fn emitter_methods_did() -> Vec<String> {
+    vec![
+        method_did(
+            "subscribe",
+            &[SubscribeRequest::ty()],
+            &[SubscribeResponse::ty()],
+            false,
+        ),
+        method_did("unsubscribe", &[UnsubscribeRequest::ty()], &[], false),
+        method_did(
+            "get_subscribers",
+            &[GetSubscribersRequest::ty()],
+            &[GetSubscribersResponse::ty()],
+            true,
+        ),
+        method_did("subscription_epoch", &[], &[u64::ty()], true),
+    ]
+}
+
+/// Returns the candid declaration of the method generated by `implement_event_catalog!()`
+pub fn event_catalog_method_did() -> String {
+    method_did("get_event_catalog", &[], &[Vec::<EventSchema>::ty()], true)
+}
+
+/// Returns the candid declaration of a listener callback, which receives batches in `format`
+///
+/// With the ordered delivery enabled on the emitter side, bare `Vec<Event>` and `Vec<CompactEvent>`
+/// batches are followed by their sequence number
+pub fn callback_method_did(method_name: &str, format: BatchFormat, ordered: bool) -> String {
+    let args = match format {
+        BatchFormat::Events if ordered => vec![Vec::<Event>::ty(), u64::ty()],
+        BatchFormat::Events => vec![Vec::<Event>::ty()],
+        BatchFormat::Envelope => vec![EventBatch::ty()],
+        BatchFormat::Compressed => vec![CompressedEventBatch::ty()],
+        BatchFormat::Compact if ordered => vec![Vec::<CompactEvent>::ty(), u64::ty()],
+        BatchFormat::Compact => vec![Vec::<CompactEvent>::ty()],
+    };
+
+    method_did(method_name, &args, &[], false)
+}
+
+/// Wraps method declarations into a service description
+///
+/// Usage:
+/// ```ignore
+/// let mut methods = emitter_methods_did();
+/// methods.push(callback_method_did("events_callback", BatchFormat::Events, false));
+///
+/// std::fs::write("can.did", service_did(&methods));
+/// ```
+pub fn service_did(methods: &[String]) -> String {
+    let mut did = String::from("service : {\n");
+
+    for method in methods {
+        did.push_str("    ");
+        did.push_str(method);
+        did.push('\n');
+    }
+
+    did.push('}');
+
+    did
+}
+
+fn method_did(name: &str, args: &[Type], rets: &[Type], query: bool) -> String {
+    format!(
+        "\"{}\" : ({}) -> ({}){};",
+        name,
+        join_types(args),
+        join_types(rets),
+        if query { " query" } else { "" }
+    )
+}
+
+fn join_types(types: &[Type]) -> String {
+    types
+        .iter()
+        .map(|ty| ty.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::did::{
+        callback_method_did, emitter_methods_did, event_catalog_method_did, service_did,
+    };
+    use crate::types::BatchFormat;
+    use candid::{check_prog, IDLProg, TypeEnv};
+
+    #[test]
+    fn generated_did_is_valid() {
+        let mut methods = emitter_methods_did();
+        methods.push(callback_method_did(
+            "events_callback",
+            BatchFormat::Events,
+            true,
+        ));
+        methods.push(callback_method_did(
+            "batch_callback",
+            BatchFormat::Envelope,
+            false,
+        ));
+        methods.push(callback_method_did(
+            "compressed_callback",
+            BatchFormat::Compressed,
+            false,
+        ));
+        methods.push(callback_method_did(
+            "compact_callback",
+            BatchFormat::Compact,
+            true,
+        ));
+        methods.push(event_catalog_method_did());
+
+        let prog: IDLProg = service_did(&methods).parse().unwrap();
+
+        let mut env = TypeEnv::new();
+        let actor = check_prog(&mut env, &prog).unwrap().unwrap();
+        let service = env.as_service(&actor).unwrap();
+
+        let mut names: Vec<_> = service.iter().map(|(name, _)| name.as_str()).collect();
+        names.sort_unstable();
+
+        assert_eq!(
+            names,
+            vec![
+                "batch_callback",
+                "compact_callback",
+                "compressed_callback",
+                "events_callback",
+                "get_event_catalog",
+                "get_subscribers",
+                "subscribe",
+                "subscription_epoch",
+                "unsubscribe"
+            ]
+        );
+    }
+}
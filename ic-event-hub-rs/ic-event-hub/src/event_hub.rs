@@ -1,38 +1,142 @@
 use candid::ser::ValueSerializer;
 use candid::{CandidType, Deserialize};
-use std::collections::{btree_map, hash_map, BinaryHeap};
-use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 use ic_cdk::export::Principal;
 
+use crate::relay::{RelayRegistry, RelayUpdate};
+use crate::storage::{EventHubStorage, HeapStorage};
 use crate::types::{
-    EncodedEventBatch, Event, EventField, EventFilter, EventHubError, RemoteCallEndpoint,
+    BatchFormat, CallbackInfo, CompactEvent, EmitReceipt, EncodedEventBatch, Event, EventField,
+    EventFilter, EventHubError, EventSchema, IEvent, RemoteCallEndpoint,
     TimestampedRemoteCallEndpoint,
 };
+use crate::{BROKER_PUBLISH_METHOD, RETRY_BASE_DELAY_NANO, RETRY_MAX_DELAY_NANO};
+
+/// How many logged events are read from the storage at once, while the log is replayed
+const EVENT_LOG_READ_CHUNK: usize = 100;
+
+/// An event passed to the hub, together with its compact form, whether it should be retained and
+/// the schema of its type
+pub(crate) struct EmittedEvent {
+    pub event: Event,
+    /// `None` if the event has no compact form
+    pub compact: Option<CompactEvent>,
+    pub retain: bool,
+    pub schema: Option<fn() -> EventSchema>,
+}
+
+impl EmittedEvent {
+    pub fn new(event: &impl IEvent) -> Self {
+        let compact = match event.to_compact_event() {
+            CompactEvent::Full(_) => None,
+            compact => Some(compact),
+        };
+
+        let retain = event.is_retained();
+        let schema = event.event_schema_fn();
+        let mut event = event.to_event();
+
+        // a broker relaying the event has to know that it is retained
+        if retain {
+            event.mark_retained();
+        }
+
+        Self {
+            event,
+            compact,
+            retain,
+            schema,
+        }
+    }
+}
+
+impl From<Event> for EmittedEvent {
+    fn from(event: Event) -> Self {
+        Self {
+            event,
+            compact: None,
+            retain: false,
+            schema: None,
+        }
+    }
+}
 
 /// A struct that associates event topics with subscribed listeners
+///
+/// All the subscriptions and batches are kept inside `storage` - on the heap by default, or in
+/// stable memory when `StableStorage` is used
 #[derive(CandidType, Deserialize)]
-pub struct EventHub {
+pub struct EventHub<S = HeapStorage> {
     pub(crate) batch_making_duration_nano: u64,
     pub(crate) batch_max_size_bytes: usize,
-    pub(crate) listeners: HashMap<EventFilter, HashSet<RemoteCallEndpoint>>,
-    pub(crate) pending_batch: HashMap<RemoteCallEndpoint, EncodedEventBatch>,
-    pub(crate) pending_batch_queue: BinaryHeap<TimestampedRemoteCallEndpoint>,
-    pub(crate) ready_batches: BTreeMap<RemoteCallEndpoint, Vec<EncodedEventBatch>>,
+    pub(crate) event_log_enabled: bool,
+    pub(crate) max_delivery_attempts: u32,
+    pub(crate) ordered_delivery: bool,
+    pub(crate) retain_unmatched_events: bool,
+    pub(crate) relays: RelayRegistry,
+    pub(crate) storage: S,
 }
 
-impl EventHub {
+impl EventHub<HeapStorage> {
     pub fn new(batch_making_duration_nano: u64, batch_max_size_bytes: usize) -> Self {
+        EventHub::with_storage(
+            batch_making_duration_nano,
+            batch_max_size_bytes,
+            HeapStorage::default(),
+        )
+    }
+
+    pub fn get_listeners(&self) -> &HashMap<EventFilter, HashSet<RemoteCallEndpoint>> {
+        self.storage.get_listeners()
+    }
+
+    /// Re-encodes topic values of stored filters and keys of retained events canonically, merging
+    /// listeners of filters which turn out to be equal. Used to migrate a state saved by a version
+    /// which stored filters the way they were subscribed with.
+    pub(crate) fn canonicalize_filters(&mut self) {
+        for (filter, endpoints) in std::mem::take(&mut self.storage.listeners) {
+            self.storage
+                .listeners
+                .entry(filter.canonicalize())
+                .or_insert_with(HashSet::new)
+                .extend(endpoints);
+        }
+
+        for (_, event) in std::mem::take(&mut self.storage.retained_events) {
+            self.storage.set_retained_event(event);
+        }
+
+        self.relays.canonicalize_filters();
+    }
+}
+
+impl<S: EventHubStorage> EventHub<S> {
+    pub fn with_storage(
+        batch_making_duration_nano: u64,
+        batch_max_size_bytes: usize,
+        storage: S,
+    ) -> Self {
         EventHub {
             batch_making_duration_nano,
             batch_max_size_bytes,
-            listeners: HashMap::default(),
-            pending_batch: HashMap::default(),
-            pending_batch_queue: BinaryHeap::new(),
-            ready_batches: BTreeMap::default(),
+            event_log_enabled: false,
+            max_delivery_attempts: 1,
+            ordered_delivery: false,
+            retain_unmatched_events: false,
+            relays: RelayRegistry::default(),
+            storage,
         }
     }
 
+    /// Returns the timestamp of the first subscription made to this hub
+    ///
+    /// Listeners compare it with the value they saw before - if it has changed, the hub was
+    /// recreated and their subscriptions might be lost
+    pub fn get_epoch(&self) -> u64 {
+        self.storage.get_epoch()
+    }
+
     pub fn set_batch_making_duration_nano(&mut self, new_duration: u64) {
         self.batch_making_duration_nano = new_duration;
     }
@@ -41,80 +145,425 @@ impl EventHub {
         self.batch_max_size_bytes = max;
     }
 
+    /// How many times a batch is sent before it is dropped, when the listener rejects it
+    pub fn set_max_delivery_attempts(&mut self, max: u32) {
+        self.max_delivery_attempts = max;
+    }
+
+    /// When enabled, at most one batch is in flight for each listener at any moment, so batches are
+    /// always received in the order of their sequence numbers (which are passed to listeners as the
+    /// second argument). Otherwise, all the ready batches are sent in parallel.
+    pub fn set_ordered_delivery(&mut self, enabled: bool) {
+        self.ordered_delivery = enabled;
+    }
+
+    pub fn is_ordered_delivery(&self) -> bool {
+        self.ordered_delivery
+    }
+
+    /// When enabled, the last event emitted with some topics while nobody listened to them is kept,
+    /// and it is sent to every listener which subscribes later with a matching filter
+    pub fn set_retain_unmatched_events(&mut self, enabled: bool) {
+        self.retain_unmatched_events = enabled;
+    }
+
+    /// When enabled, every emitted event is appended to the event log of the storage
+    pub fn set_event_log_enabled(&mut self, enabled: bool) {
+        self.event_log_enabled = enabled;
+    }
+
+    pub fn get_logged_event(&self, idx: u64) -> Option<Event> {
+        self.storage.get_logged_event(idx)
+    }
+
+    pub fn get_event_log_len(&self) -> u64 {
+        self.storage.get_event_log_len()
+    }
+
+    /// Returns schemas of the event types emitted by this hub, see `IEvent::event_schema_fn()`
+    pub fn get_event_catalog(&self) -> Vec<EventSchema> {
+        self.storage.get_event_schemas()
+    }
+
+    /// Returns the moment when the next batch should be sent
+    ///
+    /// A timestamp in the past (`0`) means that there are batches ready to be sent right away,
+    /// `None` means that there is nothing to send at all. Ready batches of a listener which has
+    /// rejected a batch are only sent once its retry deadline comes.
+    pub fn next_batch_deadline(&self) -> Option<u64> {
+        if self.relays.has_pending_updates() {
+            return Some(0);
+        }
+
+        let ready_deadline = self
+            .storage
+            .get_ready_endpoints()
+            .into_iter()
+            .filter(|endpoint| !self.ordered_delivery || !self.storage.is_in_flight(endpoint))
+            .map(|endpoint| self.storage.get_retry_deadline(&endpoint).unwrap_or(0))
+            .min();
+
+        let pending_deadline = self
+            .storage
+            .peek_queue()
+            .map(|entry| entry.timestamp + self.batch_making_duration_nano);
+
+        ready_deadline.into_iter().chain(pending_deadline).min()
+    }
+
     pub(crate) fn pop_pending_events(
         &mut self,
     ) -> Option<(RemoteCallEndpoint, Vec<EncodedEventBatch>)> {
-        let (endpoint, _) = self.ready_batches.iter_mut().next_back()?;
+        self.storage.pop_ready_batches()
+    }
+
+    /// Takes all the batches that should be sent right now
+    ///
+    /// Batches of listeners waiting for their retry deadline are left as they are. In the ordered
+    /// mode only the batch with the lowest sequence number is taken for each listener, and only if
+    /// there is no other batch in flight for it.
+    pub(crate) fn take_batches_to_send(
+        &mut self,
+        timestamp: u64,
+    ) -> Vec<(RemoteCallEndpoint, Vec<EncodedEventBatch>)> {
+        let mut ready = vec![];
+        let mut postponed = vec![];
+
+        while let Some((endpoint, batches)) = self.pop_pending_events() {
+            let retry_deadline = self.storage.get_retry_deadline(&endpoint);
+
+            if matches!(retry_deadline, Some(deadline) if deadline > timestamp) {
+                postponed.push((endpoint, batches));
+            } else {
+                ready.push((endpoint, batches));
+            }
+        }
+
+        for (endpoint, batches) in postponed {
+            for batch in batches {
+                self.storage.push_ready_batch(endpoint.clone(), batch);
+            }
+        }
+
+        if !self.ordered_delivery {
+            return ready;
+        }
+
+        let mut to_send = vec![];
 
-        let endpoint = endpoint.clone();
-        let events = self.ready_batches.remove(&endpoint).unwrap();
+        for (endpoint, mut batches) in ready {
+            if !self.storage.is_in_flight(&endpoint) {
+                batches.sort_by_key(|batch| batch.seq);
 
-        Some((endpoint, events))
+                let first = batches.remove(0);
+                self.storage.set_in_flight(endpoint.clone(), first.clone());
+
+                to_send.push((endpoint.clone(), vec![first]));
+            }
+
+            for batch in batches {
+                self.storage.push_ready_batch(endpoint.clone(), batch);
+            }
+        }
+
+        to_send
     }
 
-    pub(crate) fn push_pending_event(
+    pub(crate) fn on_batch_delivered(&mut self, endpoint: &RemoteCallEndpoint) {
+        if self.ordered_delivery {
+            self.storage.remove_in_flight(endpoint);
+        }
+
+        self.storage.set_retry_deadline(endpoint, None);
+    }
+
+    /// Puts the batches, which were in flight when the canister was upgraded, back to the ready
+    /// ones with their sequence numbers
+    ///
+    /// Calls made before an upgrade never complete, so without this their batches would be lost
+    /// and their endpoints would never receive another one in the ordered mode
+    pub fn reset_in_flight(&mut self) {
+        for (endpoint, batch) in self.storage.take_in_flight() {
+            self.storage.push_ready_batch(endpoint, batch);
+        }
+    }
+
+    /// Puts a batch which failed to be delivered back to the ready ones, unless it ran out of
+    /// delivery attempts. Returns `true` if the batch will be sent again.
+    ///
+    /// Batches are not sent to the listener until its retry deadline, which is
+    /// `RETRY_BASE_DELAY_NANO` after the first failure and doubles with each next one, up to
+    /// `RETRY_MAX_DELAY_NANO`
+    pub(crate) fn requeue_failed_batch(
         &mut self,
-        pending_event: Event,
+        endpoint: RemoteCallEndpoint,
+        mut batch: EncodedEventBatch,
         timestamp: u64,
-    ) -> Result<(), EventHubError> {
-        let listeners = self.match_event_listeners_by_topics(&pending_event.topics);
+    ) -> bool {
+        self.on_batch_delivered(&endpoint);
+        batch.delivery_attempts += 1;
 
-        if listeners.is_empty() {
-            // when nobody listens to the event it is ignored
-            return Err(EventHubError::EventHasNoActiveListeners);
+        if batch.delivery_attempts >= self.max_delivery_attempts {
+            return false;
         }
 
-        let mut event_value_ser = ValueSerializer::new();
-        pending_event
-            .idl_serialize(&mut event_value_ser)
-            .expect("Unable to serialize an event");
+        let multiplier = 1u64 << (batch.delivery_attempts - 1).min(32);
+        let delay = RETRY_BASE_DELAY_NANO
+            .saturating_mul(multiplier)
+            .min(RETRY_MAX_DELAY_NANO);
 
-        if event_value_ser.get_result().len() >= self.batch_max_size_bytes {
+        self.storage
+            .set_retry_deadline(&endpoint, Some(timestamp + delay));
+        self.storage.push_ready_batch(endpoint, batch);
+
+        true
+    }
+
+    /// Adds the schema of the event type to the catalog, unless it is there already. The schema is
+    /// only built for the first event of its type.
+    fn register_event_schema(&mut self, event: &Event, schema: fn() -> EventSchema) {
+        let registered = event
+            .find_name()
+            .map(|name| self.storage.has_event_schema(&name))
+            .unwrap_or(false);
+
+        if !registered {
+            self.storage.add_event_schema(schema());
+        }
+    }
+
+    /// Appends the event to the log, dropping the previous logged event (or tombstone) with the same
+    /// compaction key, so the log never holds more than one event per key
+    fn append_to_event_log(&mut self, event: &Event) {
+        if let Some(key) = event.get_compaction_key() {
+            if let Some(idx) = self.storage.get_latest_logged_idx(&key) {
+                self.storage.remove_logged_event(idx);
+            }
+        }
+
+        self.storage.append_to_event_log(event);
+    }
+
+    /// Checks that the event fits into a batch
+    pub(crate) fn check_event_size(&self, event: &Event) -> Result<(), EventHubError> {
+        if serialize_event(event).len() >= self.batch_max_size_bytes {
             return Err(EventHubError::EventIsTooBig);
         }
 
+        Ok(())
+    }
+
+    pub(crate) fn push_pending_event(
+        &mut self,
+        pending_event: EmittedEvent,
+        timestamp: u64,
+    ) -> Result<EmitReceipt, EventHubError> {
+        let EmittedEvent {
+            event: pending_event,
+            compact,
+            retain,
+            schema,
+        } = pending_event;
+
+        let content = serialize_event(&pending_event);
+
+        if content.len() >= self.batch_max_size_bytes {
+            return Err(EventHubError::EventIsTooBig);
+        }
+
+        if let Some(schema) = schema {
+            self.register_event_schema(&pending_event, schema);
+        }
+
+        if self.event_log_enabled {
+            self.append_to_event_log(&pending_event);
+        }
+
+        // topics are sent as they are, but compared with filters canonically
+        let topics = pending_event.canonical_topics();
+        let listeners = self.match_event_listeners_by_topics(&topics);
+        let receipt = EmitReceipt {
+            event_seq: self.storage.next_emitted_event_seq(),
+            matched_endpoints: listeners.len() as u64,
+            bytes: content.len() as u64,
+        };
+
+        // encoded only once and only if somebody needs it
+        let mut compact_content = None;
+
         for listener in listeners {
-            match self.pending_batch.entry(listener.clone()) {
-                hash_map::Entry::Vacant(e) => {
-                    let batch = EncodedEventBatch::new(event_value_ser.get_result(), timestamp);
+            if self.storage.get_batch_format(&listener) == BatchFormat::Compact {
+                let compact_content = compact_content.get_or_insert_with(|| match &compact {
+                    Some(compact) => serialize_compact_event(compact),
+                    None => serialize_compact_event(&CompactEvent::Full(pending_event.clone())),
+                });
 
-                    e.insert(batch);
+                self.push_to_listener(listener, compact_content, true, timestamp);
+            } else {
+                self.push_to_listener(listener, &content, false, timestamp);
+            }
+        }
 
-                    self.pending_batch_queue
-                        .push(TimestampedRemoteCallEndpoint {
-                            timestamp,
-                            endpoint: listener.clone(),
-                        });
-                }
-                hash_map::Entry::Occupied(mut e) => {
-                    let batch = e.get_mut();
-                    let total_size_bytes = batch.content.len() + event_value_ser.get_result().len();
-
-                    if total_size_bytes <= self.batch_max_size_bytes {
-                        batch.add_event(event_value_ser.get_result());
-                    } else {
-                        let new_batch =
-                            EncodedEventBatch::new(event_value_ser.get_result(), timestamp);
-                        let old_batch = e.insert(new_batch);
-
-                        self.pending_batch_queue
-                            .push(TimestampedRemoteCallEndpoint {
-                                timestamp,
-                                endpoint: listener.clone(),
-                            });
-
-                        self.add_ready_batch(listener, old_batch);
+        if retain || (self.retain_unmatched_events && receipt.matched_endpoints == 0) {
+            self.storage.set_retained_event(pending_event);
+        } else if self.retain_unmatched_events {
+            // the retained event is not the last one with these topics anymore
+            self.storage.remove_retained_event(&topics);
+        }
+
+        Ok(receipt)
+    }
+
+    /// Pushes events a new listener should receive with its first batches: the whole event log,
+    /// if it is enabled, and then retained events, so they stay the latest ones
+    pub(crate) fn push_initial_events(
+        &mut self,
+        listener: Principal,
+        callbacks: &[CallbackInfo],
+        timestamp: u64,
+    ) {
+        if self.event_log_enabled {
+            self.replay_event_log(listener, callbacks, timestamp);
+        }
+
+        self.push_retained_events(listener, callbacks, timestamp);
+    }
+
+    /// Pushes retained events which match the callbacks to their endpoints, so a new listener
+    /// receives them with its first batch
+    pub(crate) fn push_retained_events(
+        &mut self,
+        listener: Principal,
+        callbacks: &[CallbackInfo],
+        timestamp: u64,
+    ) {
+        for callback in callbacks {
+            let endpoint = RemoteCallEndpoint {
+                canister_id: listener,
+                method_name: callback.method_name.clone(),
+            };
+            let filter = callback.filter.clone().canonicalize();
+
+            for event in self.storage.match_retained_events(&filter) {
+                self.push_stored_event(&endpoint, event, timestamp);
+            }
+        }
+    }
+
+    /// Pushes every logged event which matches the callbacks to their endpoints, so a new listener
+    /// receives the latest state of every entity
+    pub(crate) fn replay_event_log(
+        &mut self,
+        listener: Principal,
+        callbacks: &[CallbackInfo],
+        timestamp: u64,
+    ) {
+        for callback in callbacks {
+            let endpoint = RemoteCallEndpoint {
+                canister_id: listener,
+                method_name: callback.method_name.clone(),
+            };
+            let filter = callback.filter.clone().canonicalize();
+
+            let mut from = 0;
+            loop {
+                let events = self.storage.get_logged_events(from, EVENT_LOG_READ_CHUNK);
+                let last_idx = match events.last() {
+                    Some((idx, _)) => *idx,
+                    None => break,
+                };
+
+                for (_, event) in events {
+                    if filter.0.is_subset(&event.canonical_topics()) {
+                        self.push_stored_event(&endpoint, event, timestamp);
                     }
                 }
-            };
+
+                from = last_idx + 1;
+            }
         }
+    }
 
-        Ok(())
+    fn push_stored_event(&mut self, endpoint: &RemoteCallEndpoint, event: Event, timestamp: u64) {
+        let compact = self.storage.get_batch_format(endpoint) == BatchFormat::Compact;
+        let content = if compact {
+            serialize_compact_event(&CompactEvent::Full(event))
+        } else {
+            serialize_event(&event)
+        };
+
+        self.push_to_listener(endpoint.clone(), &content, compact, timestamp);
+    }
+
+    /// Publishes all the retained events to the relay once, before its first listener is subscribed
+    ///
+    /// The relay retains them as well and pushes them to each new listener it serves, including the
+    /// one which triggered this. A listener subscribed to the relay before the events arrive gets
+    /// them with the regular delivery instead.
+    fn seed_relay(&mut self, relay: Principal, timestamp: u64) {
+        if !self.relays.mark_seeded(relay) {
+            return;
+        }
+
+        let endpoint = RemoteCallEndpoint {
+            canister_id: relay,
+            method_name: String::from(BROKER_PUBLISH_METHOD),
+        };
+
+        for mut event in self.storage.match_retained_events(&EventFilter::empty()) {
+            // unmatched events are retained without the mark
+            event.mark_retained();
+
+            self.push_to_listener(endpoint.clone(), &serialize_event(&event), false, timestamp);
+        }
+    }
+
+    /// Appends the content to the pending batch of the listener. Compact and regular events are
+    /// never mixed in a single batch, so a batch is closed when the listener switches between them.
+    fn push_to_listener(
+        &mut self,
+        listener: RemoteCallEndpoint,
+        content: &[u8],
+        compact: bool,
+        timestamp: u64,
+    ) {
+        match self.storage.get_pending_batch_meta(&listener) {
+            None => {
+                let mut batch = EncodedEventBatch::new(content, timestamp);
+                batch.compact = compact;
+
+                self.storage.start_pending_batch(listener.clone(), batch);
+                self.storage.push_to_queue(TimestampedRemoteCallEndpoint {
+                    timestamp,
+                    endpoint: listener,
+                });
+            }
+            Some(meta) => {
+                let total_size_bytes = meta.size_bytes + content.len();
+
+                if meta.compact == compact && total_size_bytes <= self.batch_max_size_bytes {
+                    self.storage.append_to_pending_batch(&listener, content);
+                } else {
+                    let old_batch = self.storage.take_pending_batch(&listener).unwrap();
+                    let mut new_batch = EncodedEventBatch::new(content, timestamp);
+                    new_batch.compact = compact;
+
+                    self.storage
+                        .start_pending_batch(listener.clone(), new_batch);
+                    self.storage.push_to_queue(TimestampedRemoteCallEndpoint {
+                        timestamp,
+                        endpoint: listener.clone(),
+                    });
+
+                    self.make_batch_ready(listener, old_batch);
+                }
+            }
+        };
     }
 
     pub(crate) fn transform_pending_to_ready_by_time(&mut self, timestamp: u64) {
         loop {
-            let cur_opt = self.pending_batch_queue.peek();
+            let cur_opt = self.storage.peek_queue();
             if cur_opt.is_none() {
                 break;
             }
@@ -125,18 +574,32 @@ impl EventHub {
                 break;
             }
 
-            let cur = self.pending_batch_queue.pop().unwrap();
-            let batch = self.pending_batch.get(&cur.endpoint).unwrap();
+            let cur = self.storage.pop_queue().unwrap();
 
-            if batch.timestamp != cur.timestamp {
-                continue;
+            // the batch this entry was pushed for could already be sent because of its size
+            match self.storage.get_pending_batch_meta(&cur.endpoint) {
+                Some(meta) if meta.timestamp == cur.timestamp => {}
+                _ => continue,
             }
 
-            let batch = self.pending_batch.remove(&cur.endpoint).unwrap();
-            self.add_ready_batch(cur.endpoint, batch);
+            let batch = self.storage.take_pending_batch(&cur.endpoint).unwrap();
+            self.make_batch_ready(cur.endpoint, batch);
+        }
+    }
+
+    fn make_batch_ready(&mut self, endpoint: RemoteCallEndpoint, mut batch: EncodedEventBatch) {
+        batch.seq = self.storage.next_batch_seq(&endpoint);
+        batch.first_event_seq = self.storage.next_event_seq(&endpoint, batch.events_count);
+        self.storage.push_ready_batch(endpoint, batch);
+    }
+
+    pub(crate) fn start_epoch(&mut self, timestamp: u64) {
+        if self.storage.get_epoch() == 0 {
+            self.storage.set_epoch(timestamp);
         }
     }
 
+    /// Topic values of the filter are re-encoded canonically, see `EventFilter::canonicalize()`
     pub fn add_event_listener(
         &mut self,
         filter: EventFilter,
@@ -148,25 +611,174 @@ impl EventHub {
             method_name: event_listener_method_name,
         };
 
-        let listeners = self.listeners.entry(filter).or_insert_with(HashSet::new);
+        self.storage.add_listener(filter.canonicalize(), listener);
+    }
+
+    /// Sets the format in which batches are passed to the listener
+    ///
+    /// The format is a property of the callback method, so the latest subscription made with this
+    /// method decides it
+    pub fn set_batch_format(
+        &mut self,
+        event_listener_method_name: String,
+        caller: Principal,
+        format: BatchFormat,
+    ) {
+        let listener = RemoteCallEndpoint {
+            canister_id: caller,
+            method_name: event_listener_method_name,
+        };
+
+        self.storage.set_batch_format(&listener, format);
+    }
+
+    pub fn get_batch_format(&self, endpoint: &RemoteCallEndpoint) -> BatchFormat {
+        self.storage.get_batch_format(endpoint)
+    }
+
+    /// Subscribes a broker canister to all the events of this hub, so they are sent to the broker
+    /// in batches and the broker delivers them to its own listeners
+    pub fn forward_to_broker(&mut self, broker: Principal) {
+        self.add_event_listener(
+            EventFilter::empty(),
+            String::from(BROKER_PUBLISH_METHOD),
+            broker,
+        );
+    }
 
-        listeners.insert(listener);
+    /// Adds a relay canister, which is subscribed to all the events of this hub and serves a share
+    /// of its listeners
+    ///
+    /// Once there is at least one relay, new listeners are assigned to relays instead of being
+    /// served by this hub directly. Listeners subscribed before that are still served directly.
+    pub fn add_relay(&mut self, relay: Principal) {
+        self.relays.add_relay(relay);
+        self.forward_to_broker(relay);
+    }
+
+    /// Removes a relay canister, moving its listeners to other relays, or back to this hub if it
+    /// was the last one
+    pub fn remove_relay(&mut self, relay: Principal) {
+        let _ = self.remove_event_listener(
+            &EventFilter::empty(),
+            String::from(BROKER_PUBLISH_METHOD),
+            relay,
+        );
+
+        for (listener, callbacks) in self.relays.remove_relay(&relay) {
+            self.add_callbacks(listener, callbacks);
+        }
+    }
+
+    pub fn get_relays(&self) -> Vec<Principal> {
+        self.relays.get_relays()
+    }
+
+    pub fn get_relay_of(&self, listener: &Principal) -> Option<Principal> {
+        self.relays.get_relay_of(listener)
+    }
+
+    /// Tells the hub which subnet a listener or a relay canister is on, so listeners could be
+    /// served by relays on their own subnet
+    pub fn set_subnet(&mut self, canister_id: Principal, subnet: Principal) {
+        self.relays.set_subnet(canister_id, subnet);
+    }
+
+    pub fn get_subnet(&self, canister_id: &Principal) -> Option<Principal> {
+        self.relays.get_subnet(canister_id)
+    }
+
+    /// Moves listeners to relays on their own subnets and evens out the number of listeners served
+    /// by each relay. Returns the number of moved listeners.
+    pub fn rebalance_relays(&mut self) -> usize {
+        self.relays.rebalance()
+    }
+
+    /// Allows `upstream` to subscribe listeners to this hub on their behalf, which makes this hub a
+    /// relay of `upstream`
+    pub fn add_relay_upstream(&mut self, upstream: Principal) {
+        self.relays.add_upstream(upstream);
+    }
+
+    pub fn is_relay_upstream(&self, canister_id: &Principal) -> bool {
+        self.relays.is_upstream(canister_id)
+    }
+
+    /// Subscribes the listener either directly or via its relay, if there are any relays
+    pub(crate) fn subscribe_listener(
+        &mut self,
+        listener: Principal,
+        callbacks: Vec<CallbackInfo>,
+        timestamp: u64,
+    ) {
+        if self.relays.has_relays() && !self.relays.is_relay(&listener) {
+            if let Some(relay) = self.relays.subscribe(listener, callbacks) {
+                self.seed_relay(relay, timestamp);
+            }
+        } else {
+            // initial events are encoded according to the batch formats of the callbacks
+            self.add_callbacks(listener, callbacks.clone());
+            self.push_initial_events(listener, &callbacks, timestamp);
+        }
     }
 
+    /// Unsubscribes the listener either via its relay, if it has one, or directly
+    pub(crate) fn unsubscribe_listener(
+        &mut self,
+        listener: Principal,
+        callbacks: Vec<CallbackInfo>,
+    ) -> Result<(), String> {
+        if self.relays.get_relay_of(&listener).is_some() {
+            self.relays.unsubscribe(listener, callbacks);
+
+            return Ok(());
+        }
+
+        for (idx, callback) in callbacks.into_iter().enumerate() {
+            self.remove_event_listener(&callback.filter, callback.method_name, listener)
+                .map_err(|e| format!("Unable to remove listener #{} - {}", idx, e))?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn add_callbacks(&mut self, listener: Principal, callbacks: Vec<CallbackInfo>) {
+        for callback in callbacks {
+            if let Some(format) = callback.format {
+                self.set_batch_format(callback.method_name.clone(), listener, format);
+            }
+
+            self.add_event_listener(callback.filter, callback.method_name, listener);
+        }
+    }
+
+    pub(crate) fn take_relay_updates(&mut self) -> Vec<RelayUpdate> {
+        self.relays.take_pending_updates()
+    }
+
+    pub(crate) fn requeue_relay_updates(&mut self, updates: Vec<RelayUpdate>) {
+        self.relays.requeue_updates(updates);
+    }
+
+    /// Returns listeners which receive events having all the topics of the filter. Topic values
+    /// are compared canonically, whatever types they were encoded with.
     pub fn match_event_listeners(&self, filter: &EventFilter) -> Vec<RemoteCallEndpoint> {
-        self.match_event_listeners_by_topics(&filter.0)
+        self.match_event_listeners_by_topics(&filter.clone().canonicalize().0)
+    }
+
+    /// Returns callbacks of listeners served by relays, which match the filter
+    pub fn match_relayed_listeners(&self, filter: &EventFilter) -> Vec<RemoteCallEndpoint> {
+        self.relays
+            .match_listeners(&filter.clone().canonicalize().0)
     }
 
+    /// Same as `match_event_listeners()`, but the topics should be canonical already, see
+    /// `Event::canonical_topics()`
     pub fn match_event_listeners_by_topics(
         &self,
         topics: &BTreeSet<EventField>,
     ) -> Vec<RemoteCallEndpoint> {
-        self.listeners
-            .iter()
-            .filter(|&entry| entry.0 .0.is_subset(topics))
-            .map(|entry| entry.1.clone())
-            .flatten()
-            .collect()
+        self.storage.match_listeners(topics)
     }
 
     pub fn remove_event_listener(
@@ -175,46 +787,48 @@ impl EventHub {
         event_listener_method_name: String,
         caller: Principal,
     ) -> Result<(), String> {
-        let listeners = self
-            .listeners
-            .get_mut(filter)
-            .ok_or_else(|| String::from("No such filter"))?;
-
         let listener_to_remove = RemoteCallEndpoint {
             canister_id: caller,
             method_name: event_listener_method_name,
         };
 
-        let res = listeners.remove(&listener_to_remove);
-
-        if !res {
-            Err(String::from("No such listener in that filter"))
-        } else {
-            Ok(())
-        }
+        self.storage
+            .remove_listener(&filter.clone().canonicalize(), &listener_to_remove)
     }
+}
 
-    pub fn get_listeners(&self) -> &HashMap<EventFilter, HashSet<RemoteCallEndpoint>> {
-        &self.listeners
-    }
+fn serialize_event(event: &Event) -> Vec<u8> {
+    let mut event_value_ser = ValueSerializer::new();
+    event
+        .idl_serialize(&mut event_value_ser)
+        .expect("Unable to serialize an event");
 
-    fn add_ready_batch(&mut self, listener: RemoteCallEndpoint, batch: EncodedEventBatch) {
-        match self.ready_batches.entry(listener) {
-            btree_map::Entry::Vacant(e) => {
-                e.insert(vec![batch]);
-            }
-            btree_map::Entry::Occupied(mut e) => {
-                e.get_mut().push(batch);
-            }
-        }
-    }
+    event_value_ser.get_result().to_vec()
+}
+
+fn serialize_compact_event(event: &CompactEvent) -> Vec<u8> {
+    let mut event_value_ser = ValueSerializer::new();
+    event
+        .idl_serialize(&mut event_value_ser)
+        .expect("Unable to serialize an event");
+
+    event_value_ser.get_result().to_vec()
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::event_hub::EventHub;
-    use crate::types::{EventField, EventFilter, RemoteCallEndpoint};
-    use candid::Principal;
+    use crate::event_hub::{EmittedEvent, EventHub};
+    use crate::fns::encode_batch_message;
+    use crate::storage::EventHubStorage;
+    use crate::types::{
+        BatchFormat, CallbackInfo, CompactEvent, EncodedEventBatch, Event, EventField, EventFilter,
+        EventHubError, IEvent, RemoteCallEndpoint, TimestampedRemoteCallEndpoint,
+    };
+    use crate::{BROKER_PUBLISH_METHOD, EVENT_KEY_FIELD, RETRY_BASE_DELAY_NANO};
+    use candid::{decode_one, encode_one, Principal};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use std::collections::{BTreeSet, BinaryHeap, HashMap};
     use std::time::{SystemTime, UNIX_EPOCH};
 
     pub fn random_principal_test() -> Principal {
@@ -355,4 +969,606 @@ mod tests {
         assert_eq!(endpoints.len(), 1, "Should match filter #1_2_3");
         assert!(endpoints.contains(&endpoint_3), "Should contain endpoint 3");
     }
+
+    #[test]
+    fn next_batch_deadline_works_fine() {
+        let mut event_hub = EventHub::new(10, 1024);
+        assert_eq!(event_hub.next_batch_deadline(), None);
+
+        let endpoint = RemoteCallEndpoint {
+            canister_id: random_principal_test(),
+            method_name: String::from("test"),
+        };
+        event_hub.add_event_listener(
+            EventFilter::empty(),
+            endpoint.method_name.clone(),
+            endpoint.canister_id,
+        );
+
+        let event = Event {
+            topics: BTreeSet::new(),
+            values: vec![],
+        };
+
+        event_hub
+            .push_pending_event(event.clone().into(), 5)
+            .unwrap();
+        event_hub.push_pending_event(event.into(), 7).unwrap();
+        assert_eq!(event_hub.next_batch_deadline(), Some(15));
+
+        event_hub.transform_pending_to_ready_by_time(15);
+        assert_eq!(event_hub.next_batch_deadline(), Some(0));
+
+        event_hub.pop_pending_events().unwrap();
+        assert_eq!(event_hub.next_batch_deadline(), None);
+    }
+
+    #[test]
+    fn failed_batches_are_sent_again_with_backoff() {
+        let mut event_hub = EventHub::new(10, 1024);
+        event_hub.set_max_delivery_attempts(3);
+
+        let endpoint = RemoteCallEndpoint {
+            canister_id: random_principal_test(),
+            method_name: String::from("test"),
+        };
+        let batch = EncodedEventBatch::new(&[], 0);
+
+        assert!(event_hub.requeue_failed_batch(endpoint.clone(), batch, 100));
+        assert_eq!(
+            event_hub.next_batch_deadline(),
+            Some(100 + RETRY_BASE_DELAY_NANO)
+        );
+        assert!(event_hub.take_batches_to_send(100).is_empty());
+
+        let mut sent = event_hub.take_batches_to_send(100 + RETRY_BASE_DELAY_NANO);
+        assert_eq!(sent.len(), 1);
+
+        // the delay doubles with each failed attempt
+        let (_, mut batches) = sent.remove(0);
+        assert!(event_hub.requeue_failed_batch(endpoint.clone(), batches.remove(0), 200));
+        assert_eq!(
+            event_hub.next_batch_deadline(),
+            Some(200 + 2 * RETRY_BASE_DELAY_NANO)
+        );
+
+        let (_, mut batches) = event_hub.take_batches_to_send(u64::MAX).remove(0);
+        assert!(!event_hub.requeue_failed_batch(endpoint.clone(), batches.remove(0), 300));
+        assert_eq!(event_hub.next_batch_deadline(), None);
+
+        event_hub.on_batch_delivered(&endpoint);
+        assert_eq!(event_hub.storage.get_retry_deadline(&endpoint), None);
+    }
+
+    #[test]
+    fn receipts_and_retained_events_work_fine() {
+        let mut event_hub = EventHub::new(10, 1024);
+        event_hub.set_retain_unmatched_events(true);
+
+        let field = EventField {
+            name: String::from("1"),
+            value: vec![1],
+        };
+        let event = Event {
+            topics: vec![field.clone()].into_iter().collect(),
+            values: vec![],
+        };
+
+        let receipt = event_hub
+            .push_pending_event(event.clone().into(), 0)
+            .unwrap();
+        assert_eq!(receipt.event_seq, 0);
+        assert_eq!(receipt.matched_endpoints, 0);
+        assert!(receipt.bytes > 0);
+        assert_eq!(event_hub.next_batch_deadline(), None);
+
+        let listener = random_principal_test();
+        let callback = CallbackInfo {
+            filter: EventFilter(vec![field].into_iter().collect()),
+            method_name: String::from("test"),
+            format: None,
+        };
+
+        // the retained event is queued for the new listener
+        event_hub.subscribe_listener(listener, vec![callback], 5);
+        assert_eq!(event_hub.next_batch_deadline(), Some(15));
+
+        let receipt = event_hub.push_pending_event(event.into(), 6).unwrap();
+        assert_eq!(receipt.event_seq, 1);
+        assert_eq!(receipt.matched_endpoints, 1);
+
+        // the retained event is stale now, so later listeners won't receive it
+        assert!(event_hub
+            .storage
+            .match_retained_events(&EventFilter::empty())
+            .is_empty());
+
+        event_hub.transform_pending_to_ready_by_time(15);
+        let (endpoint, batches) = event_hub.pop_pending_events().unwrap();
+        assert_eq!(endpoint.canister_id, listener);
+        assert_eq!(batches[0].events_count, 2);
+    }
+
+    #[test]
+    fn compact_events_are_pushed_to_compact_callbacks_only() {
+        let mut event_hub = EventHub::new(10, 1024);
+
+        let regular = random_principal_test();
+        let compact = random_principal_test();
+        let callback = |format| CallbackInfo {
+            filter: EventFilter::empty(),
+            method_name: String::from("test"),
+            format,
+        };
+
+        event_hub.subscribe_listener(regular, vec![callback(None)], 0);
+        event_hub.subscribe_listener(compact, vec![callback(Some(BatchFormat::Compact))], 0);
+
+        let event = Event {
+            topics: BTreeSet::new(),
+            values: vec![EventField {
+                name: String::from("price"),
+                value: encode_one(100u64).unwrap(),
+            }],
+        };
+        let compact_event = CompactEvent::Fields {
+            name: String::from("PriceEvent"),
+            values: encode_one(100u64).unwrap(),
+        };
+
+        event_hub
+            .push_pending_event(
+                EmittedEvent {
+                    event: event.clone(),
+                    compact: Some(compact_event),
+                    retain: false,
+                    schema: None,
+                },
+                0,
+            )
+            .unwrap();
+        // an event without a compact form is sent as it is
+        event_hub
+            .push_pending_event(event.clone().into(), 1)
+            .unwrap();
+
+        let endpoint = |canister_id| RemoteCallEndpoint {
+            canister_id,
+            method_name: String::from("test"),
+        };
+
+        let batch = event_hub
+            .storage
+            .take_pending_batch(&endpoint(regular))
+            .unwrap();
+        assert!(!batch.compact);
+        let events: Vec<Event> = decode_one(&encode_batch_message(&batch, false)).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].values, event.values);
+
+        let batch = event_hub
+            .storage
+            .take_pending_batch(&endpoint(compact))
+            .unwrap();
+        assert!(batch.compact);
+        let events: Vec<CompactEvent> = decode_one(&encode_batch_message(&batch, false)).unwrap();
+        assert_eq!(events.len(), 2);
+        match &events[0] {
+            CompactEvent::Fields { name, values } => {
+                assert_eq!(name, "PriceEvent");
+                assert_eq!(decode_one::<u64>(values).unwrap(), 100);
+            }
+            CompactEvent::Full(_) => panic!("Expected a compact event"),
+        }
+        match &events[1] {
+            CompactEvent::Full(full) => assert_eq!(full.values, event.values),
+            CompactEvent::Fields { .. } => panic!("Expected a full event"),
+        }
+    }
+
+    #[test]
+    fn retained_events_work_fine() {
+        let mut event_hub = EventHub::new(10, 1024);
+
+        let callback = CallbackInfo {
+            filter: EventFilter::empty(),
+            method_name: String::from("test"),
+            format: None,
+        };
+        let price_event = |price: u64| EmittedEvent {
+            event: Event {
+                topics: BTreeSet::new(),
+                values: vec![EventField {
+                    name: String::from("price"),
+                    value: encode_one(price).unwrap(),
+                }],
+            },
+            compact: None,
+            retain: true,
+            schema: None,
+        };
+
+        let listener_1 = Principal::from_slice(&[1]);
+        event_hub.subscribe_listener(listener_1, vec![callback.clone()], 0);
+
+        event_hub.push_pending_event(price_event(1), 0).unwrap();
+        event_hub.push_pending_event(price_event(2), 1).unwrap();
+
+        // only the latest event is retained
+        let retained = event_hub
+            .storage
+            .match_retained_events(&EventFilter::empty());
+        assert_eq!(retained.len(), 1);
+        assert_eq!(decode_one::<u64>(&retained[0].values[0].value).unwrap(), 2);
+
+        event_hub.transform_pending_to_ready_by_time(10);
+        let (_, batches) = event_hub.pop_pending_events().unwrap();
+        assert_eq!(batches[0].events_count, 2);
+
+        let listener_2 = Principal::from_slice(&[2]);
+        event_hub.subscribe_listener(listener_2, vec![callback], 20);
+        assert_eq!(event_hub.next_batch_deadline(), Some(30));
+
+        event_hub.transform_pending_to_ready_by_time(30);
+        let (endpoint, batches) = event_hub.pop_pending_events().unwrap();
+        assert_eq!(endpoint.canister_id, listener_2);
+        assert_eq!(batches[0].events_count, 1);
+    }
+
+    #[test]
+    fn retained_events_are_encoded_for_compact_callbacks() {
+        let mut event_hub = EventHub::new(10, 1024);
+
+        let event = EmittedEvent {
+            event: Event {
+                topics: BTreeSet::new(),
+                values: vec![],
+            },
+            compact: None,
+            retain: true,
+            schema: None,
+        };
+        event_hub.push_pending_event(event, 0).unwrap();
+
+        let listener = Principal::from_slice(&[1]);
+        let callback = CallbackInfo {
+            filter: EventFilter::empty(),
+            method_name: String::from("compact_callback"),
+            format: Some(BatchFormat::Compact),
+        };
+        event_hub.subscribe_listener(listener, vec![callback], 20);
+
+        event_hub.transform_pending_to_ready_by_time(30);
+        let (_, batches) = event_hub.pop_pending_events().unwrap();
+        assert!(batches[0].compact);
+
+        let events: Vec<CompactEvent> =
+            decode_one(&encode_batch_message(&batches[0], false)).unwrap();
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn retained_events_are_passed_to_relays() {
+        let mut event_hub = EventHub::new(10, 1024);
+
+        let callback = CallbackInfo {
+            filter: EventFilter::empty(),
+            method_name: String::from("test"),
+            format: None,
+        };
+        let event = EmittedEvent {
+            event: Event {
+                topics: BTreeSet::new(),
+                values: vec![],
+            },
+            compact: None,
+            retain: true,
+            schema: None,
+        };
+        event_hub.push_pending_event(event, 0).unwrap();
+
+        // the relay is added after the event is retained, so it has never received it
+        let relay = Principal::from_slice(&[3]);
+        event_hub.add_relay(relay);
+
+        event_hub.subscribe_listener(Principal::from_slice(&[1]), vec![callback.clone()], 20);
+        event_hub.subscribe_listener(Principal::from_slice(&[2]), vec![callback], 20);
+
+        event_hub.transform_pending_to_ready_by_time(30);
+        let (endpoint, batches) = event_hub.pop_pending_events().unwrap();
+        assert_eq!(endpoint.canister_id, relay);
+        assert_eq!(endpoint.method_name, BROKER_PUBLISH_METHOD);
+        assert_eq!(batches.len(), 1);
+
+        let events: Vec<Event> = decode_one(&encode_batch_message(&batches[0], false)).unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].is_retained());
+
+        assert!(event_hub.pop_pending_events().is_none());
+    }
+
+    #[test]
+    fn retain_flag_is_forwarded_to_brokers() {
+        struct PriceEvent;
+
+        impl IEvent for PriceEvent {
+            fn to_event(&self) -> Event {
+                Event {
+                    topics: BTreeSet::new(),
+                    values: vec![],
+                }
+            }
+
+            fn from_event(_: Event) -> Self {
+                PriceEvent
+            }
+
+            fn is_retained(&self) -> bool {
+                true
+            }
+        }
+
+        let emitted = EmittedEvent::new(&PriceEvent);
+        assert!(emitted.retain);
+
+        // the payload stays the same and the event is matched by its own topics only
+        assert!(emitted.event.values.is_empty());
+        assert_eq!(
+            emitted.event.canonical_topics(),
+            PriceEvent.to_event().canonical_topics()
+        );
+
+        // a broker only receives the event itself and still retains it
+        let relayed = EmittedEvent::new(&emitted.event);
+        assert!(relayed.retain);
+
+        let plain = EmittedEvent::new(&PriceEvent.to_event());
+        assert!(!plain.retain);
+    }
+
+    #[test]
+    fn filters_are_matched_canonically() {
+        let mut event_hub = EventHub::new(10, 1024);
+
+        let listener = random_principal_test();
+        let field = |value| EventField {
+            name: String::from("amount"),
+            value,
+        };
+
+        // the filter is built with `nat64`, while the event is emitted with `nat32`
+        event_hub.add_event_listener(
+            EventFilter(vec![field(encode_one(5u64).unwrap())].into_iter().collect()),
+            String::from("test"),
+            listener,
+        );
+
+        let event = Event {
+            topics: vec![field(encode_one(5u32).unwrap())].into_iter().collect(),
+            values: vec![],
+        };
+        let receipt = event_hub.push_pending_event(event.into(), 0).unwrap();
+        assert_eq!(receipt.matched_endpoints, 1);
+
+        event_hub
+            .remove_event_listener(
+                &EventFilter(vec![field(encode_one(5u64).unwrap())].into_iter().collect()),
+                String::from("test"),
+                listener,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn event_log_compaction_works_fine() {
+        let mut event_hub = EventHub::new(10, 1024);
+        event_hub.set_event_log_enabled(true);
+
+        let keyed_event = |key: Vec<u8>, value: u8| Event {
+            topics: vec![EventField {
+                name: String::from(EVENT_KEY_FIELD),
+                value: key,
+            }]
+            .into_iter()
+            .collect(),
+            values: vec![EventField {
+                name: String::from("value"),
+                value: vec![value],
+            }],
+        };
+        let plain_event = Event {
+            topics: BTreeSet::new(),
+            values: vec![],
+        };
+
+        // keys are compared canonically, whatever integer type they were encoded with
+        let events = vec![
+            keyed_event(encode_one(1u64).unwrap(), 1),
+            keyed_event(encode_one(2u64).unwrap(), 1),
+            plain_event.clone(),
+            keyed_event(encode_one(1u32).unwrap(), 2),
+            keyed_event(encode_one(2u64).unwrap(), 2).into_tombstone(),
+            plain_event,
+        ];
+        for event in events {
+            event_hub.push_pending_event(event.into(), 0).unwrap();
+        }
+
+        // events which are too big are not logged
+        let big_event = Event {
+            topics: BTreeSet::new(),
+            values: vec![EventField {
+                name: String::from("value"),
+                value: vec![0; 1024],
+            }],
+        };
+        assert!(event_hub.push_pending_event(big_event.into(), 0).is_err());
+
+        // compacted on append, indices of the kept events stay the same
+        assert_eq!(event_hub.get_event_log_len(), 4);
+        assert!(event_hub.get_logged_event(0).is_none());
+        assert!(event_hub.get_logged_event(1).is_none());
+
+        let first = event_hub.get_logged_event(3).unwrap();
+        assert_eq!(first.values[0].value, vec![2]);
+
+        let tombstone = event_hub.get_logged_event(4).unwrap();
+        assert!(tombstone.is_tombstone());
+        assert!(tombstone.values.is_empty());
+
+        // a new listener receives the whole compacted log
+        let listener = Principal::from_slice(&[1]);
+        let callback = CallbackInfo {
+            filter: EventFilter::empty(),
+            method_name: String::from("test"),
+            format: None,
+        };
+        event_hub.subscribe_listener(listener, vec![callback], 20);
+
+        event_hub.transform_pending_to_ready_by_time(30);
+        let (endpoint, batches) = event_hub.pop_pending_events().unwrap();
+        assert_eq!(endpoint.canister_id, listener);
+
+        let events: Vec<Event> = decode_one(&encode_batch_message(&batches[0], false)).unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[1].values[0].value, vec![2]);
+        assert!(events[2].is_tombstone());
+    }
+
+    fn topic(idx: u8) -> EventField {
+        EventField {
+            name: format!("topic_{}", idx),
+            value: vec![idx],
+        }
+    }
+
+    fn topics_by_mask(mask: u8) -> BTreeSet<EventField> {
+        (0..3).filter(|i| mask & (1 << i) != 0).map(topic).collect()
+    }
+
+    fn drain_ready_batches(
+        event_hub: &mut EventHub,
+        delivered: &mut HashMap<RemoteCallEndpoint, Vec<u64>>,
+    ) {
+        while let Some((endpoint, batches)) = event_hub.pop_pending_events() {
+            for batch in batches {
+                assert!(
+                    batch.content.len() <= event_hub.batch_max_size_bytes,
+                    "Batch should fit the size limit"
+                );
+
+                let events: Vec<Event> = decode_one(&encode_batch_message(&batch, false)).unwrap();
+                assert_eq!(events.len(), batch.events_count);
+
+                delivered.entry(endpoint.clone()).or_default().extend(
+                    events
+                        .iter()
+                        .map(|event| decode_one::<u64>(&event.values[0].value).unwrap()),
+                );
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn every_event_is_batched_exactly_once_in_order(
+            batch_making_duration_nano in 1..100u64,
+            batch_max_size_bytes in 50..400usize,
+            filter_masks in vec(0..8u8, 1..4),
+            events in vec((0..8u8, 0..64usize, 0..30u64, any::<bool>()), 0..50),
+        ) {
+            let mut event_hub = EventHub::new(batch_making_duration_nano, batch_max_size_bytes);
+
+            let listeners: Vec<_> = filter_masks
+                .iter()
+                .enumerate()
+                .map(|(idx, mask)| {
+                    let endpoint = RemoteCallEndpoint {
+                        canister_id: Principal::from_slice(&[idx as u8]),
+                        method_name: format!("callback_{}", idx),
+                    };
+                    event_hub.add_event_listener(
+                        EventFilter(topics_by_mask(*mask)),
+                        endpoint.method_name.clone(),
+                        endpoint.canister_id,
+                    );
+
+                    (topics_by_mask(*mask), endpoint)
+                })
+                .collect();
+
+            let mut expected: HashMap<RemoteCallEndpoint, Vec<u64>> = HashMap::new();
+            let mut delivered: HashMap<RemoteCallEndpoint, Vec<u64>> = HashMap::new();
+            let mut now = 0;
+
+            for (idx, event_params) in events.into_iter().enumerate() {
+                let (topics_mask, payload_len, time_delta, transform) = event_params;
+                now += time_delta;
+
+                let event = Event {
+                    topics: topics_by_mask(topics_mask),
+                    values: vec![
+                        EventField {
+                            name: String::from("idx"),
+                            value: encode_one(idx as u64).unwrap(),
+                        },
+                        EventField {
+                            name: String::from("payload"),
+                            value: vec![0; payload_len],
+                        },
+                    ],
+                };
+
+                let matched: Vec<_> = listeners
+                    .iter()
+                    .filter(|(filter, _)| filter.is_subset(&event.topics))
+                    .map(|(_, endpoint)| endpoint.clone())
+                    .collect();
+
+                match event_hub.push_pending_event(event.into(), now) {
+                    Ok(receipt) => {
+                        prop_assert_eq!(receipt.matched_endpoints, matched.len() as u64);
+
+                        for endpoint in matched {
+                            expected.entry(endpoint).or_default().push(idx as u64);
+                        }
+                    }
+                    Err(EventHubError::EventIsTooBig) => {}
+                }
+
+                if transform {
+                    event_hub.transform_pending_to_ready_by_time(now);
+                    drain_ready_batches(&mut event_hub, &mut delivered);
+                }
+            }
+
+            event_hub.transform_pending_to_ready_by_time(now + batch_making_duration_nano);
+            drain_ready_batches(&mut event_hub, &mut delivered);
+
+            prop_assert_eq!(event_hub.next_batch_deadline(), None);
+            prop_assert_eq!(delivered, expected);
+        }
+
+        #[test]
+        fn pending_batch_queue_pops_oldest_first(timestamps in vec(any::<u64>(), 0..50)) {
+            let mut queue = BinaryHeap::new();
+
+            for timestamp in timestamps.iter() {
+                queue.push(TimestampedRemoteCallEndpoint {
+                    timestamp: *timestamp,
+                    endpoint: RemoteCallEndpoint {
+                        canister_id: Principal::anonymous(),
+                        method_name: String::from("test"),
+                    },
+                });
+            }
+
+            let mut sorted = timestamps;
+            sorted.sort_unstable();
+
+            let popped: Vec<_> =
+                std::iter::from_fn(|| queue.pop().map(|entry| entry.timestamp)).collect();
+            prop_assert_eq!(popped, sorted);
+        }
+    }
 }
@@ -1,84 +1,411 @@
-use crate::event_hub::EventHub;
+use std::collections::BTreeSet;
+use std::thread::LocalKey;
+use std::time::Duration;
+
+use crate::event_hub::{EmittedEvent, EventHub};
+use crate::runtime::Runtime;
+use crate::state::{EventHubAccess, EventHubCell};
+use crate::storage::EventHubStorage;
+use crate::subscription_registry::with_subscription_registry;
+use crate::transaction::EventTransaction;
 use crate::types::{
-    Event, EventHubError, GetSubscribersRequest, GetSubscribersResponse, IEvent, SubscribeRequest,
-    UnsubscribeRequest,
+    BatchFormat, CallbackInfo, CompactEvent, CompressedEventBatch, EmitReceipt, EncodedEventBatch,
+    Event, EventBatch, EventHubError, EventSchema, FailedDelivery, GetSubscribersRequest,
+    GetSubscribersResponse, IEvent, RelaySubscribeRequest, RelayUnsubscribeRequest, SendReport,
+    SubscribeRequest, SubscribeResponse, UnsubscribeRequest,
 };
-use candid::ser::TypeSerialize;
-use candid::CandidType;
-use futures::future;
-use ic_cdk::api::call::call_raw;
-use ic_cdk::api::time;
-use ic_cdk::{caller, id, print, trap};
+use crate::EVENT_EMITTER_FIELD;
+use candid::ser::{TypeSerialize, ValueSerializer};
+use candid::{encode_one, idl_hash, CandidType};
+use futures::stream::{FuturesUnordered, StreamExt};
+use ic_cdk::export::Principal;
+use ic_cdk::trap;
 
-pub fn emit_impl(event: impl IEvent, hub: &mut EventHub) -> Result<(), EventHubError> {
-    print(format!("[Canister {}] - ic_event_hub.emit()", id()));
+/// Pushes the event to the hub
+///
+/// An event nobody listens to is not an error - its receipt simply has no matched endpoints
+pub fn emit_impl<S: EventHubStorage>(
+    event: impl IEvent,
+    hub: &mut EventHub<S>,
+    runtime: &impl Runtime,
+) -> Result<EmitReceipt, EventHubError> {
+    runtime.log(format!("[Canister {}] - ic_event_hub.emit()", runtime.id()));
 
-    hub.push_pending_event(event.to_event(), time())
+    hub.push_pending_event(EmittedEvent::new(&event), runtime.time())
 }
 
-pub fn send_events_impl(hub: &mut EventHub) {
-    hub.transform_pending_to_ready_by_time(time());
+/// Pushes all the events of the transaction to the hub, or none of them if any of them is too big.
+/// Returns receipts of the events in the order they were emitted.
+pub fn commit_impl<S: EventHubStorage>(
+    transaction: EventTransaction,
+    hub: &mut EventHub<S>,
+    runtime: &impl Runtime,
+) -> Result<Vec<EmitReceipt>, EventHubError> {
+    runtime.log(format!(
+        "[Canister {}] - ic_event_hub.commit_events()",
+        runtime.id()
+    ));
 
-    let mut emit_futures = vec![];
+    let events = transaction.into_events();
+    for emitted in events.iter() {
+        hub.check_event_size(&emitted.event)?;
+    }
 
-    loop {
-        let batches_opt = hub.pop_pending_events();
+    let now = runtime.time();
 
-        if batches_opt.is_none() {
-            break;
-        }
+    events
+        .into_iter()
+        .map(|emitted| hub.push_pending_event(emitted, now))
+        .collect()
+}
 
-        let (endpoint, batches) = batches_opt.unwrap();
+pub async fn send_events_async_impl<S: EventHubStorage>(
+    hub: &impl EventHubAccess<S>,
+    runtime: &impl Runtime,
+) -> SendReport {
+    send_relay_updates(hub, runtime).await;
 
-        print(format!(
-            "[Canister {}]: heartbeat - ic_event_hub.send_events()",
-            id()
-        ));
+    let now = runtime.time();
+    let (ready, ordered) = hub.with_hub(|hub| {
+        hub.transform_pending_to_ready_by_time(now);
 
-        let mut type_ser = TypeSerialize::new();
-        type_ser
-            .push_type(&Vec::<Event>::ty())
-            .expect("Unable to push type");
-        type_ser.serialize().expect("Unable to serialize types");
+        let ready: Vec<_> = hub
+            .take_batches_to_send(now)
+            .into_iter()
+            .map(|(endpoint, batches)| {
+                let format = hub.get_batch_format(&endpoint);
+
+                (endpoint, batches, format)
+            })
+            .collect();
+
+        (ready, hub.is_ordered_delivery())
+    });
+
+    let mut report = SendReport::default();
+
+    if ready.is_empty() {
+        return report;
+    }
+
+    runtime.log(format!(
+        "[Canister {}]: ic_event_hub.send_events()",
+        runtime.id()
+    ));
+
+    let mut deliveries = FuturesUnordered::new();
 
+    for (endpoint, batches, format) in ready {
         for batch in batches {
-            let mut msg: Vec<u8> = vec![];
-            msg.extend_from_slice(b"DIDL");
-            msg.extend_from_slice(type_ser.get_result());
-            leb128::write::unsigned(&mut msg, batch.events_count as u64)
-                .expect("Unable to write len");
-            msg.extend_from_slice(&batch.content);
-
-            emit_futures.push(call_raw(
-                endpoint.canister_id,
-                endpoint.method_name.as_str(),
-                msg,
-                0,
+            let msg = match format {
+                // the callback has switched formats since these compact events were pushed
+                _ if batch.compact => encode_batch_message(&batch, ordered),
+                BatchFormat::Events | BatchFormat::Compact => encode_batch_message(&batch, ordered),
+                BatchFormat::Envelope => encode_envelope_message(&batch, runtime.id()),
+                BatchFormat::Compressed => encode_compressed_message(&batch),
+            };
+            let endpoint = endpoint.clone();
+            let call = runtime.call_raw(endpoint.canister_id, endpoint.method_name.as_str(), msg);
+
+            deliveries.push(async move { (endpoint, batch, call.await) });
+        }
+    }
+
+    while let Some((endpoint, batch, res)) = deliveries.next().await {
+        match res {
+            Ok(_) => {
+                hub.with_hub(|hub| hub.on_batch_delivered(&endpoint));
+
+                report.delivered_batches += 1;
+                report.delivered_events += batch.events_count as u64;
+            }
+            Err((rejection_code, message)) => {
+                let events_count = batch.events_count;
+                let will_retry =
+                    hub.with_hub(|hub| hub.requeue_failed_batch(endpoint.clone(), batch, now));
+
+                report.failed.push(FailedDelivery {
+                    endpoint,
+                    events_count,
+                    rejection_code,
+                    message,
+                    will_retry,
+                });
+            }
+        }
+    }
+
+    report
+}
+
+/// Sends queued subscription changes to relays, one by one and in the order they were made
+///
+/// Once a relay rejects a change, the rest of the changes for that relay are not sent, so they are
+/// never applied out of order. Unsent changes are put back to the front of the queue and are
+/// retried on the next delivery.
+async fn send_relay_updates<S: EventHubStorage>(
+    hub: &impl EventHubAccess<S>,
+    runtime: &impl Runtime,
+) {
+    let updates = hub.with_hub(|hub| hub.take_relay_updates());
+
+    let mut failed_relays = BTreeSet::new();
+    let mut unsent = vec![];
+
+    for update in updates {
+        if failed_relays.contains(&update.relay()) {
+            unsent.push(update);
+            continue;
+        }
+
+        let msg = encode_one(update.request()).expect("Unable to encode relay update");
+        let res = runtime
+            .call_raw(update.relay(), update.method_name(), msg)
+            .await;
+
+        if let Err((rejection_code, message)) = res {
+            runtime.log(format!(
+                "[Canister {}]: ic_event_hub relay {} rejected {}() - {:?} {}",
+                runtime.id(),
+                update.relay(),
+                update.method_name(),
+                rejection_code,
+                message
             ));
+
+            failed_relays.insert(update.relay());
+            unsent.push(update);
         }
     }
 
-    if !emit_futures.is_empty() {
-        ic_cdk::block_on(async {
-            future::join_all(emit_futures).await;
-        });
+    if !unsent.is_empty() {
+        hub.with_hub(|hub| hub.requeue_relay_updates(unsent));
+    }
+}
+
+/// Calls `set_timer` with the delay until the next batch deadline, unless there is nothing to send
+/// or a timer for an earlier (or the same) deadline is already set
+pub fn schedule_delivery_impl<S: EventHubStorage>(
+    hub: &'static LocalKey<EventHubCell<S>>,
+    runtime: &impl Runtime,
+    set_timer: impl FnOnce(Duration),
+) {
+    let now = runtime.time();
+
+    let delay = hub.with(|cell| {
+        let deadline = cell.with(|hub| hub.next_batch_deadline())?;
+
+        if matches!(cell.get_scheduled_delivery(), Some(scheduled) if scheduled <= deadline) {
+            return None;
+        }
+
+        let deadline = deadline.max(now);
+        cell.set_scheduled_delivery(deadline);
+
+        Some(Duration::from_nanos(deadline - now))
+    });
+
+    if let Some(delay) = delay {
+        set_timer(delay);
+    }
+}
+
+/// Wraps pre-encoded events of the batch into a candid message of a single `Vec<Event>` (or
+/// `Vec<CompactEvent>`) argument, followed by the `nat64` sequence number of the batch if
+/// `with_seq` is set
+pub(crate) fn encode_batch_message(batch: &EncodedEventBatch, with_seq: bool) -> Vec<u8> {
+    let events_ty = if batch.compact {
+        Vec::<CompactEvent>::ty()
+    } else {
+        Vec::<Event>::ty()
+    };
+
+    let mut type_ser = TypeSerialize::new();
+    type_ser.push_type(&events_ty).expect("Unable to push type");
+    if with_seq {
+        type_ser.push_type(&u64::ty()).expect("Unable to push type");
+    }
+    type_ser.serialize().expect("Unable to serialize types");
+
+    let mut msg: Vec<u8> = vec![];
+    msg.extend_from_slice(b"DIDL");
+    msg.extend_from_slice(type_ser.get_result());
+    leb128::write::unsigned(&mut msg, batch.events_count as u64).expect("Unable to write len");
+    msg.extend_from_slice(&batch.content);
+    if with_seq {
+        msg.extend_from_slice(&batch.seq.to_le_bytes());
+    }
+
+    msg
+}
+
+/// Wraps pre-encoded events of the batch into a candid message of a single `EventBatch` argument
+///
+/// Record fields are serialized in the order of their ids, as candid requires, and the events are
+/// copied as they are, without re-encoding them
+pub(crate) fn encode_envelope_message(batch: &EncodedEventBatch, emitter: Principal) -> Vec<u8> {
+    let mut type_ser = TypeSerialize::new();
+    type_ser
+        .push_type(&EventBatch::ty())
+        .expect("Unable to push type");
+    type_ser.serialize().expect("Unable to serialize types");
+
+    let mut events = vec![];
+    leb128::write::unsigned(&mut events, batch.events_count as u64).expect("Unable to write len");
+    events.extend_from_slice(&batch.content);
+
+    let mut fields = vec![
+        (idl_hash("emitter"), serialize_value(&emitter)),
+        (idl_hash("batch_seq"), serialize_value(&batch.seq)),
+        (
+            idl_hash("first_event_seq"),
+            serialize_value(&batch.first_event_seq),
+        ),
+        (idl_hash("created_at"), serialize_value(&batch.timestamp)),
+        (idl_hash("events"), events),
+    ];
+    fields.sort_by_key(|(id, _)| *id);
+
+    let mut msg: Vec<u8> = vec![];
+    msg.extend_from_slice(b"DIDL");
+    msg.extend_from_slice(type_ser.get_result());
+    for (_, value) in fields {
+        msg.extend_from_slice(&value);
+    }
+
+    msg
+}
+
+/// Wraps pre-encoded events of the batch into a candid message of a single `CompressedEventBatch`
+/// argument
+pub(crate) fn encode_compressed_message(batch: &EncodedEventBatch) -> Vec<u8> {
+    let compressed = CompressedEventBatch::compress(
+        batch.seq,
+        batch.events_count as u64,
+        &encode_batch_message(batch, false),
+    );
+
+    encode_one(compressed).expect("Unable to encode a compressed batch")
+}
+
+fn serialize_value(value: &impl CandidType) -> Vec<u8> {
+    let mut value_ser = ValueSerializer::new();
+    value
+        .idl_serialize(&mut value_ser)
+        .expect("Unable to serialize a value");
+
+    value_ser.get_result().to_vec()
+}
+
+pub fn subscribe_impl<S: EventHubStorage>(
+    request: SubscribeRequest,
+    hub: &mut EventHub<S>,
+    runtime: &impl Runtime,
+) -> SubscribeResponse {
+    let now = runtime.time();
+    let listener = runtime.caller();
+
+    hub.start_epoch(now);
+    hub.subscribe_listener(listener, request.callbacks, now);
+
+    // the listener may be moved to any other relay later
+    let relays = match hub.get_relay_of(&listener) {
+        Some(_) => hub.get_relays(),
+        None => vec![],
+    };
+
+    SubscribeResponse { relays }
+}
+
+/// Subscribes a listener on behalf of the upstream emitter, which made this hub one of its relays
+///
+/// Batches are sent to the listener by this hub, so a relayed listener should either accept
+/// batches from unknown emitters or subscribe to the relay itself
+pub fn subscribe_for_impl<S: EventHubStorage>(
+    request: RelaySubscribeRequest,
+    hub: &mut EventHub<S>,
+    runtime: &impl Runtime,
+) {
+    check_relay_upstream(hub, runtime);
+
+    let now = runtime.time();
+    let callbacks = from_upstream(request.callbacks, runtime.caller());
+
+    hub.start_epoch(now);
+    // initial events are encoded according to the batch formats of the callbacks
+    hub.add_callbacks(request.listener, callbacks.clone());
+    hub.push_initial_events(request.listener, &callbacks, now);
+}
+
+/// Unsubscribes a listener on behalf of the upstream emitter
+///
+/// Unlike `unsubscribe_impl()` it ignores callbacks that are not subscribed, so the upstream could
+/// safely retry it
+pub fn unsubscribe_for_impl<S: EventHubStorage>(
+    request: RelayUnsubscribeRequest,
+    hub: &mut EventHub<S>,
+    runtime: &impl Runtime,
+) {
+    check_relay_upstream(hub, runtime);
+
+    for callback in from_upstream(request.callbacks, runtime.caller()) {
+        let _ = hub.remove_event_listener(&callback.filter, callback.method_name, request.listener);
+    }
+}
+
+/// Narrows filters of relayed callbacks down to events published by the upstream, so a relay
+/// which also serves as a broker of other emitters never mixes their events up
+fn from_upstream(callbacks: Vec<CallbackInfo>, upstream: Principal) -> Vec<CallbackInfo> {
+    callbacks
+        .into_iter()
+        .map(|mut callback| {
+            callback.filter = callback.filter.from_emitter(upstream);
+            callback
+        })
+        .collect()
+}
+
+fn check_relay_upstream<S: EventHubStorage>(hub: &EventHub<S>, runtime: &impl Runtime) {
+    let caller = runtime.caller();
+
+    if !hub.is_relay_upstream(&caller) {
+        trap(format!("Canister {} is not an upstream of this relay", caller).as_str());
     }
 }
 
-pub fn subscribe_impl(request: SubscribeRequest, hub: &mut EventHub) {
-    for callback in request.callbacks.into_iter() {
-        hub.add_event_listener(callback.filter, callback.method_name, caller());
+pub fn subscription_epoch_impl<S: EventHubStorage>(hub: &EventHub<S>) -> u64 {
+    hub.get_epoch()
+}
+
+/// Returns schemas of the emitted event types, followed by the listed ones which were not emitted
+/// yet
+pub fn get_event_catalog_impl<S: EventHubStorage>(
+    hub: &EventHub<S>,
+    listed: Vec<EventSchema>,
+) -> Vec<EventSchema> {
+    let mut catalog = hub.get_event_catalog();
+
+    for schema in listed {
+        if !catalog.iter().any(|it| it.name == schema.name) {
+            catalog.push(schema);
+        }
     }
+
+    catalog
 }
 
-pub fn get_subscriers_impl(
+pub fn get_subscriers_impl<S: EventHubStorage>(
     request: GetSubscribersRequest,
-    hub: &mut EventHub,
+    hub: &mut EventHub<S>,
 ) -> GetSubscribersResponse {
     let mut listeners = vec![];
 
-    for filter in request.filters.iter() {
-        listeners.push(hub.match_event_listeners(filter));
+    for filter in request.filters {
+        let mut matched = hub.match_event_listeners(&filter);
+        // listeners served by relays receive the events as well
+        matched.extend(hub.match_relayed_listeners(&filter));
+
+        listeners.push(matched);
     }
 
     GetSubscribersResponse {
@@ -86,27 +413,581 @@ pub fn get_subscriers_impl(
     }
 }
 
-pub fn unsubscribe_impl(request: UnsubscribeRequest, hub: &mut EventHub) {
-    for (idx, listener) in request.callbacks.into_iter().enumerate() {
-        let res = hub.remove_event_listener(&listener.filter, listener.method_name, caller());
+pub fn unsubscribe_impl<S: EventHubStorage>(
+    request: UnsubscribeRequest,
+    hub: &mut EventHub<S>,
+    runtime: &impl Runtime,
+) {
+    if let Err(e) = hub.unsubscribe_listener(runtime.caller(), request.callbacks) {
+        trap(e.as_str());
+    }
+}
 
-        if res.is_err() {
-            trap(
-                format!(
-                    "Unable to remove listener #{} - {}",
-                    idx,
-                    res.err().unwrap()
-                )
-                .as_str(),
-            );
-        }
+pub fn check_event_sender(method_name: &str, runtime: &impl Runtime) {
+    let emitter = runtime.caller();
+
+    if !with_subscription_registry(|registry| registry.is_sender(&emitter, method_name)) {
+        trap(
+            format!(
+                "Rejected an event batch from unknown emitter {} in {}",
+                emitter, method_name
+            )
+            .as_str(),
+        );
+    }
+}
+
+/// Rejects the batch if one of its events has an emitter topic which is not a principal, so
+/// handlers can rely on `Event::get_emitter()` of relayed events
+pub fn check_event_emitters<'a>(method_name: &str, events: impl IntoIterator<Item = &'a Event>) {
+    let malformed = events.into_iter().any(|event| {
+        event
+            .topics
+            .iter()
+            .any(|field| field.name == EVENT_EMITTER_FIELD)
+            && event.get_emitter().is_none()
+    });
+
+    if malformed {
+        trap(
+            format!(
+                "Rejected an event batch with a malformed emitter topic in {}",
+                method_name
+            )
+            .as_str(),
+        );
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::event_hub::EventHub;
+    use crate::fns::{
+        commit_impl, emit_impl, encode_batch_message, encode_compressed_message,
+        encode_envelope_message, get_event_catalog_impl, get_subscriers_impl,
+        send_events_async_impl, subscribe_for_impl, subscribe_impl, unsubscribe_impl,
+    };
+    use crate::runtime::MockRuntime;
+    use crate::state::EventHubCell;
+    use crate::transaction::EventTransaction;
+    use crate::types::{
+        CallbackInfo, CompressedEventBatch, EncodedEventBatch, Event, EventBatch, EventField,
+        EventFilter, EventHubError, EventSchema, GetSubscribersRequest, IEvent,
+        RelaySubscribeRequest, RemoteCallEndpoint, SubscribeRequest,
+    };
+    use crate::{
+        BROKER_PUBLISH_METHOD, EVENT_EMITTER_FIELD, EVENT_NAME_FIELD, MAX_UNCOMPRESSED_BATCH_SIZE,
+        RELAY_SUBSCRIBE_METHOD, RETRY_BASE_DELAY_NANO,
+    };
     use candid::ser::{TypeSerialize, ValueSerializer};
-    use candid::{encode_args, encode_one, CandidType, Nat};
+    use candid::{decode_args, decode_one, encode_args, encode_one, CandidType, Nat, Principal};
+    use futures::executor::block_on;
+    use ic_cdk::api::call::RejectionCode;
+    use std::cell::RefCell;
+    use std::collections::BTreeSet;
+
+    thread_local! {
+        static HUB: EventHubCell = EventHubCell::new(|| EventHub::new(10, 1024));
+    }
+
+    struct TestEvent;
+
+    impl IEvent for TestEvent {
+        fn to_event(&self) -> Event {
+            Event {
+                topics: BTreeSet::new(),
+                values: vec![],
+            }
+        }
+
+        fn from_event(_: Event) -> Self {
+            TestEvent
+        }
+    }
+
+    #[test]
+    fn malformed_emitters_are_not_decoded() {
+        let emitter = Principal::from_slice(&[1]);
+
+        let mut event = Event {
+            topics: vec![EventField {
+                name: String::from(EVENT_EMITTER_FIELD),
+                value: vec![1, 2, 3],
+            }]
+            .into_iter()
+            .collect(),
+            values: vec![],
+        };
+        assert!(event.get_emitter().is_none());
+
+        event.set_emitter(emitter);
+        assert_eq!(event.get_emitter(), Some(emitter));
+    }
+
+    #[test]
+    fn emit_and_delivery_work_fine() {
+        let runtime = MockRuntime::new(Principal::from_slice(&[1]));
+        let listener = RemoteCallEndpoint {
+            canister_id: Principal::from_slice(&[2]),
+            method_name: String::from("events_callback"),
+        };
+
+        runtime.set_caller(listener.canister_id);
+        HUB.with(|cell| {
+            cell.with(|hub| {
+                hub.set_max_delivery_attempts(2);
+
+                let request = SubscribeRequest {
+                    callbacks: vec![CallbackInfo {
+                        filter: EventFilter::empty(),
+                        method_name: listener.method_name.clone(),
+                        format: None,
+                    }],
+                };
+                subscribe_impl(request, hub, &runtime);
+
+                emit_impl(TestEvent, hub, &runtime).unwrap();
+                emit_impl(TestEvent, hub, &runtime).unwrap();
+            })
+        });
+
+        // the batch is not ready yet
+        runtime.set_time(5);
+        let report = block_on(send_events_async_impl(&HUB, &runtime));
+        assert_eq!(report.delivered_batches, 0);
+        assert!(runtime.take_calls().is_empty());
+
+        runtime.set_time(10);
+        runtime.reject_calls_to(listener.clone(), RejectionCode::CanisterError, "Oops");
+        let report = block_on(send_events_async_impl(&HUB, &runtime));
+        assert_eq!(report.failed.len(), 1);
+        assert!(report.failed[0].will_retry);
+        assert_eq!(runtime.take_calls().len(), 1);
+
+        // the batch is not sent again until the retry deadline
+        let deadline = HUB.with(|cell| cell.with(|hub| hub.next_batch_deadline()));
+        assert_eq!(deadline, Some(10 + RETRY_BASE_DELAY_NANO));
+
+        runtime.accept_calls_to(&listener);
+        let report = block_on(send_events_async_impl(&HUB, &runtime));
+        assert_eq!(report.delivered_batches, 0);
+        assert!(runtime.take_calls().is_empty());
+
+        runtime.set_time(10 + RETRY_BASE_DELAY_NANO);
+        let report = block_on(send_events_async_impl(&HUB, &runtime));
+        assert_eq!(report.delivered_batches, 1);
+        assert_eq!(report.delivered_events, 2);
+
+        let calls = runtime.take_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].canister_id, listener.canister_id);
+
+        let events: Vec<Event> = decode_one(&calls[0].args).unwrap();
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn event_schemas_are_registered_when_emitted() {
+        struct PriceEvent;
+
+        impl IEvent for PriceEvent {
+            fn to_event(&self) -> Event {
+                Event {
+                    topics: vec![EventField {
+                        name: String::from(EVENT_NAME_FIELD),
+                        value: encode_one("PriceEvent").unwrap(),
+                    }]
+                    .into_iter()
+                    .collect(),
+                    values: vec![],
+                }
+            }
+
+            fn from_event(_: Event) -> Self {
+                PriceEvent
+            }
+
+            fn event_schema_fn(&self) -> Option<fn() -> EventSchema> {
+                Some(|| EventSchema {
+                    name: String::from("PriceEvent"),
+                    version: 1,
+                    topics: vec![],
+                    values: vec![],
+                })
+            }
+        }
+
+        let runtime = MockRuntime::new(Principal::from_slice(&[1]));
+        let mut hub = EventHub::new(10, 1024);
+        let listed = || {
+            vec![EventSchema {
+                name: String::from("ListedEvent"),
+                version: 1,
+                topics: vec![],
+                values: vec![],
+            }]
+        };
+
+        runtime.set_caller(Principal::from_slice(&[2]));
+        let request = SubscribeRequest {
+            callbacks: vec![CallbackInfo {
+                filter: EventFilter::empty(),
+                method_name: String::from("events_callback"),
+                format: None,
+            }],
+        };
+        subscribe_impl(request, &mut hub, &runtime);
+
+        // events without a schema are not cataloged
+        emit_impl(TestEvent, &mut hub, &runtime).unwrap();
+        assert!(hub.get_event_catalog().is_empty());
+
+        emit_impl(PriceEvent, &mut hub, &runtime).unwrap();
+        emit_impl(PriceEvent, &mut hub, &runtime).unwrap();
+
+        let catalog = hub.get_event_catalog();
+        assert_eq!(catalog.len(), 1);
+        assert_eq!(catalog[0].name, "PriceEvent");
+
+        // listed event types are returned even if they were not emitted yet
+        let catalog = get_event_catalog_impl(&hub, listed());
+        assert_eq!(catalog.len(), 2);
+        assert_eq!(catalog[1].name, "ListedEvent");
+    }
+
+    #[test]
+    fn transactions_work_fine() {
+        let runtime = MockRuntime::new(Principal::from_slice(&[1]));
+        let mut hub = EventHub::new(10, 1024);
+        hub.add_event_listener(
+            EventFilter::empty(),
+            String::from("events_callback"),
+            Principal::from_slice(&[2]),
+        );
+
+        let mut tx = EventTransaction::new();
+        tx.emit(TestEvent);
+        tx.emit(TestEvent);
+        assert_eq!(tx.len(), 2);
+
+        tx.rollback();
+        assert!(hub.next_batch_deadline().is_none());
+
+        // a single event that is too big discards the whole transaction
+        let mut tx = EventTransaction::new();
+        tx.emit(TestEvent);
+        tx.emit(Event {
+            topics: BTreeSet::new(),
+            values: vec![EventField {
+                name: String::from("data"),
+                value: vec![0u8; 2048],
+            }],
+        });
+        assert!(matches!(
+            commit_impl(tx, &mut hub, &runtime),
+            Err(EventHubError::EventIsTooBig)
+        ));
+        assert!(hub.next_batch_deadline().is_none());
+
+        let mut tx = EventTransaction::new();
+        tx.emit(TestEvent);
+        tx.emit(TestEvent);
+        commit_impl(tx, &mut hub, &runtime).unwrap();
+        assert_eq!(hub.next_batch_deadline(), Some(10));
+
+        runtime.set_time(10);
+        let report = block_on(send_events_async_impl(&RefCell::new(hub), &runtime));
+        assert_eq!(report.delivered_events, 2);
+    }
+
+    #[test]
+    fn relayed_subscriptions_work_fine() {
+        let root = Principal::from_slice(&[1]);
+        let listener = Principal::from_slice(&[2]);
+        let relay = Principal::from_slice(&[3]);
+
+        let runtime = MockRuntime::new(root);
+        let hub = RefCell::new(EventHub::new(10, 1024));
+        hub.borrow_mut().add_relay(relay);
+
+        let request = SubscribeRequest {
+            callbacks: vec![CallbackInfo {
+                filter: EventFilter::empty(),
+                method_name: String::from("events_callback"),
+                format: None,
+            }],
+        };
+
+        runtime.set_caller(listener);
+        let response = subscribe_impl(request.clone(), &mut hub.borrow_mut(), &runtime);
+        assert_eq!(hub.borrow().get_relay_of(&listener), Some(relay));
+        assert_eq!(response.relays, vec![relay]);
+
+        // relayed listeners are reported as subscribers of the root
+        let subscribers = get_subscriers_impl(
+            GetSubscribersRequest {
+                filters: vec![EventFilter::empty()],
+            },
+            &mut hub.borrow_mut(),
+        );
+        assert!(subscribers.subscribers[0].contains(&RemoteCallEndpoint {
+            canister_id: listener,
+            method_name: String::from("events_callback"),
+        }));
+
+        emit_impl(TestEvent, &mut hub.borrow_mut(), &runtime).unwrap();
+        runtime.set_time(10);
+        let report = block_on(send_events_async_impl(&hub, &runtime));
+        assert_eq!(report.delivered_batches, 1);
+
+        // the subscription is passed to the relay first, then the relay receives the batch
+        let calls = runtime.take_calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].method_name, RELAY_SUBSCRIBE_METHOD);
+        assert_eq!(calls[1].canister_id, relay);
+        assert_eq!(calls[1].method_name, BROKER_PUBLISH_METHOD);
+
+        let relayed: RelaySubscribeRequest = decode_one(&calls[0].args).unwrap();
+        assert_eq!(relayed.listener, listener);
+
+        let relay_runtime = MockRuntime::new(relay);
+        let mut relay_hub = EventHub::new(10, 1024);
+        relay_hub.add_relay_upstream(root);
+
+        relay_runtime.set_caller(root);
+        subscribe_for_impl(relayed, &mut relay_hub, &relay_runtime);
+        assert_eq!(
+            relay_hub.match_event_listeners(&EventFilter::empty().from_emitter(root)),
+            vec![RemoteCallEndpoint {
+                canister_id: listener,
+                method_name: String::from("events_callback"),
+            }]
+        );
+
+        // events published to the relay by other emitters are not delivered to relayed listeners
+        assert!(relay_hub
+            .match_event_listeners(&EventFilter::empty().from_emitter(listener))
+            .is_empty());
+
+        unsubscribe_impl(request, &mut hub.borrow_mut(), &runtime);
+        assert!(hub.borrow().get_relay_of(&listener).is_none());
+    }
+
+    #[test]
+    fn subscribers_are_matched_canonically() {
+        let runtime = MockRuntime::new(Principal::from_slice(&[1]));
+        let mut hub = EventHub::new(10, 1024);
+
+        let filter = |value| {
+            EventFilter(
+                vec![EventField {
+                    name: String::from("amount"),
+                    value,
+                }]
+                .into_iter()
+                .collect(),
+            )
+        };
+
+        runtime.set_caller(Principal::from_slice(&[2]));
+        let request = SubscribeRequest {
+            callbacks: vec![CallbackInfo {
+                filter: filter(encode_one(5u32).unwrap()),
+                method_name: String::from("events_callback"),
+                format: None,
+            }],
+        };
+        subscribe_impl(request, &mut hub, &runtime);
+
+        // the same value of another candid type
+        let response = get_subscriers_impl(
+            GetSubscribersRequest {
+                filters: vec![filter(encode_one(Nat::from(5u64)).unwrap())],
+            },
+            &mut hub,
+        );
+        assert_eq!(response.subscribers[0].len(), 1);
+    }
+
+    #[test]
+    fn relay_updates_are_not_reordered_by_failures() {
+        let root = Principal::from_slice(&[1]);
+        let relay = Principal::from_slice(&[3]);
+
+        let runtime = MockRuntime::new(root);
+        let hub = RefCell::new(EventHub::new(10, 1024));
+        hub.borrow_mut().add_relay(relay);
+
+        let request = SubscribeRequest {
+            callbacks: vec![CallbackInfo {
+                filter: EventFilter::empty(),
+                method_name: String::from("events_callback"),
+                format: None,
+            }],
+        };
+
+        for i in 10..13 {
+            runtime.set_caller(Principal::from_slice(&[i]));
+            subscribe_impl(request.clone(), &mut hub.borrow_mut(), &runtime);
+        }
+
+        let endpoint = RemoteCallEndpoint {
+            canister_id: relay,
+            method_name: String::from(RELAY_SUBSCRIBE_METHOD),
+        };
+        runtime.reject_calls_to(endpoint.clone(), RejectionCode::SysTransient, "busy");
+
+        // nothing is sent to the relay after its first failure
+        block_on(send_events_async_impl(&hub, &runtime));
+        assert_eq!(runtime.take_calls().len(), 1);
+
+        runtime.accept_calls_to(&endpoint);
+        block_on(send_events_async_impl(&hub, &runtime));
+
+        let listeners: Vec<_> = runtime
+            .take_calls()
+            .iter()
+            .map(|call| {
+                decode_one::<RelaySubscribeRequest>(&call.args)
+                    .unwrap()
+                    .listener
+            })
+            .collect();
+        assert_eq!(
+            listeners,
+            (10..13)
+                .map(|i| Principal::from_slice(&[i]))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn ordered_delivery_works_fine() {
+        let runtime = MockRuntime::new(Principal::from_slice(&[1]));
+        let listener = RemoteCallEndpoint {
+            canister_id: Principal::from_slice(&[2]),
+            method_name: String::from("events_callback"),
+        };
+
+        runtime.set_caller(listener.canister_id);
+        HUB.with(|cell| {
+            cell.with(|hub| {
+                hub.set_ordered_delivery(true);
+                // each batch fits only a single event
+                hub.set_max_batch_size(3);
+
+                let request = SubscribeRequest {
+                    callbacks: vec![CallbackInfo {
+                        filter: EventFilter::empty(),
+                        method_name: listener.method_name.clone(),
+                        format: None,
+                    }],
+                };
+                subscribe_impl(request, hub, &runtime);
+
+                for _ in 0..3 {
+                    emit_impl(TestEvent, hub, &runtime).unwrap();
+                }
+            })
+        });
+
+        runtime.set_time(10);
+
+        for expected_seq in 0..3u64 {
+            let report = block_on(send_events_async_impl(&HUB, &runtime));
+            assert_eq!(report.delivered_batches, 1);
+
+            let calls = runtime.take_calls();
+            assert_eq!(calls.len(), 1);
+
+            let (events, seq): (Vec<Event>, u64) = decode_args(&calls[0].args).unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(seq, expected_seq);
+        }
+
+        let report = block_on(send_events_async_impl(&HUB, &runtime));
+        assert_eq!(report.delivered_batches, 0);
+    }
+
+    #[test]
+    fn envelope_encoding_matches_candid() {
+        let event = Event {
+            topics: vec![EventField {
+                name: String::from("topic"),
+                value: vec![1, 2, 3],
+            }]
+            .into_iter()
+            .collect(),
+            values: vec![EventField {
+                name: String::from("value"),
+                value: vec![4, 5],
+            }],
+        };
+
+        let mut value_ser = ValueSerializer::new();
+        event.idl_serialize(&mut value_ser).unwrap();
+
+        let mut batch = EncodedEventBatch::new(value_ser.get_result(), 100);
+        batch.add_event(value_ser.get_result());
+        batch.seq = 7;
+        batch.first_event_seq = 42;
+
+        let emitter = Principal::from_slice(&[1, 2, 3]);
+        let expected = encode_one(EventBatch {
+            emitter,
+            batch_seq: 7,
+            first_event_seq: 42,
+            created_at: 100,
+            events: vec![event.clone(), event],
+        })
+        .unwrap();
+
+        assert_eq!(encode_envelope_message(&batch, emitter), expected);
+    }
+
+    #[test]
+    fn compression_saves_bytes() {
+        let event = Event {
+            topics: vec![EventField {
+                name: String::from(crate::EVENT_NAME_FIELD),
+                value: encode_one("PriceUpdatedEvent").unwrap(),
+            }]
+            .into_iter()
+            .collect(),
+            values: vec![EventField {
+                name: String::from("price"),
+                value: encode_one(100u64).unwrap(),
+            }],
+        };
+
+        let mut value_ser = ValueSerializer::new();
+        event.idl_serialize(&mut value_ser).unwrap();
+
+        let mut batch = EncodedEventBatch::new(value_ser.get_result(), 0);
+        for _ in 1..100 {
+            batch.add_event(value_ser.get_result());
+        }
+
+        let uncompressed_size = encode_batch_message(&batch, false).len();
+        let msg = encode_compressed_message(&batch);
+
+        // repeated field names are compressed away almost entirely
+        assert!(msg.len() * 10 < uncompressed_size);
+
+        let compressed: CompressedEventBatch = decode_one(&msg).unwrap();
+        assert_eq!(compressed.uncompressed_size, uncompressed_size as u64);
+
+        let events = compressed.decompress().unwrap();
+        assert_eq!(events.len(), 100);
+        assert_eq!(events[99].values, event.values);
+
+        // a batch claiming to be too big is rejected before anything is allocated
+        let forged = CompressedEventBatch {
+            uncompressed_size: MAX_UNCOMPRESSED_BATCH_SIZE + 1,
+            ..compressed
+        };
+        assert!(forged.decompress().is_err());
+    }
 
     #[test]
     fn tst() {
@@ -11,8 +11,72 @@ pub mod event_hub;
 /// Various structs and traits
 pub mod types;
 
+/// Storage backends for the event-hub
+pub mod storage;
+
+/// Stable memory storage backend for the event-hub, enabled by the `stable-memory` feature
+#[cfg(feature = "stable-memory")]
+pub mod stable_storage;
+
+/// System calls used by the event-hub, abstracted for deterministic tests
+pub mod runtime;
+
+/// Thread-local container for the event-hub of a canister, used by macros
+pub mod state;
+
+/// Versioned event-hub state to be saved between upgrades
+pub mod upgrade;
+
+/// Listener-side registry of emitters this canister is subscribed to
+pub mod subscription_registry;
+
+/// Candid interface helpers for emitter and listener endpoints
+pub mod did;
+
+/// Buffered emission of events, committed to the event-hub at once
+pub mod transaction;
+
+/// Sharding of listeners across relay canisters
+pub mod relay;
+
+/// Canonical encoding of topic values
+pub mod topic;
+
 /// Lower level function to be used inside macros
 pub mod fns;
 
 /// Marker that enables event name serialization
 pub const EVENT_NAME_FIELD: &str = "__event_name";
+
+/// Delay before a batch rejected by its listener is sent again, doubled with each failed attempt
+pub const RETRY_BASE_DELAY_NANO: u64 = 1_000_000_000;
+
+/// The longest delay between two delivery attempts of a batch
+pub const RETRY_MAX_DELAY_NANO: u64 = 5 * 60 * 1_000_000_000;
+
+/// Marker of the topic that holds the original emitter of an event relayed by a broker
+pub const EVENT_EMITTER_FIELD: &str = "__emitter";
+
+/// Marker of the topic that holds the compaction key of an event, set with `#[topic(key)]`
+pub const EVENT_KEY_FIELD: &str = "__key";
+
+/// Marker of the topic that turns an event into a tombstone of its compaction key
+pub const EVENT_TOMBSTONE_FIELD: &str = "__tombstone";
+
+/// Marker of the topic that makes a broker retain the event, set for events emitted with
+/// `#[event(retain)]`. It is not a part of `Event::canonical_topics()`, so it doesn't affect
+/// matching.
+pub const EVENT_RETAINED_FIELD: &str = "__retained";
+
+/// The biggest batch a `CompressedEventBatch` is allowed to decompress into - four times the
+/// message size limit of the IC, so a forged `uncompressed_size` can't exhaust the heap
+pub const MAX_UNCOMPRESSED_BATCH_SIZE: u64 = 4 * 2 * 1024 * 1024;
+
+/// Name of the broker method that receives event batches from emitters
+pub const BROKER_PUBLISH_METHOD: &str = "publish";
+
+/// Name of the relay method that receives subscriptions made on behalf of listeners
+pub const RELAY_SUBSCRIBE_METHOD: &str = "subscribe_for";
+
+/// Name of the relay method that receives unsubscriptions made on behalf of listeners
+pub const RELAY_UNSUBSCRIBE_METHOD: &str = "unsubscribe_for";
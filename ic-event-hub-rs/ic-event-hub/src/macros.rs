@@ -1,35 +1,192 @@
 #[macro_export]
 macro_rules! implement_event_emitter {
-    ($duration:expr, $max_size:expr) => {
-        static mut _EVENT_HUB: Option<ic_event_hub::event_hub::EventHub> = None;
-
-        pub fn get_event_hub() -> &'static mut ic_event_hub::event_hub::EventHub {
-            unsafe {
-                if let Some(s) = &mut _EVENT_HUB {
-                    s
-                } else {
-                    _EVENT_HUB = Some(ic_event_hub::event_hub::EventHub::new($duration, $max_size));
-                    get_event_hub()
-                }
-            }
+    (@state $storage:ty, $init:expr) => {
+        thread_local! {
+            static _EVENT_HUB: ic_event_hub::state::EventHubCell<$storage> =
+                ic_event_hub::state::EventHubCell::new(|| $init);
+        }
+
+        pub fn with_event_hub<R>(
+            f: impl FnOnce(&mut ic_event_hub::event_hub::EventHub<$storage>) -> R,
+        ) -> R {
+            _EVENT_HUB.with(|hub| hub.with(f))
+        }
+
+        pub fn emit(
+            event: impl ic_event_hub::types::IEvent,
+        ) -> Result<ic_event_hub::types::EmitReceipt, ic_event_hub::types::EventHubError> {
+            let result = with_event_hub(|hub| {
+                ic_event_hub::fns::emit_impl(event, hub, &ic_event_hub::runtime::IcRuntime)
+            });
+            _event_hub_schedule_delivery();
+
+            result
         }
 
+        pub fn commit_events(
+            transaction: ic_event_hub::transaction::EventTransaction,
+        ) -> Result<Vec<ic_event_hub::types::EmitReceipt>, ic_event_hub::types::EventHubError> {
+            let result = with_event_hub(|hub| {
+                ic_event_hub::fns::commit_impl(transaction, hub, &ic_event_hub::runtime::IcRuntime)
+            });
+            _event_hub_schedule_delivery();
+
+            result
+        }
+
+        pub async fn send_events_async() -> ic_event_hub::types::SendReport {
+            let report = ic_event_hub::fns::send_events_async_impl(
+                &_EVENT_HUB,
+                &ic_event_hub::runtime::IcRuntime,
+            )
+            .await;
+            _event_hub_schedule_delivery();
+
+            report
+        }
+
+        pub fn send_events() {
+            ic_cdk::block_on(async {
+                send_events_async().await;
+            });
+        }
+    };
+
+    (@schedule) => {
+        pub fn _event_hub_schedule_delivery() {}
+    };
+
+    (@schedule $set_timer:expr) => {
+        pub fn _event_hub_schedule_delivery() {
+            ic_event_hub::fns::schedule_delivery_impl(
+                &_EVENT_HUB,
+                &ic_event_hub::runtime::IcRuntime,
+                |delay| {
+                    let _ = ($set_timer)(delay, _event_hub_deliver_on_timer);
+                },
+            );
+        }
+
+        fn _event_hub_deliver_on_timer() {
+            _EVENT_HUB.with(|hub| hub.clear_scheduled_delivery(ic_cdk::api::time()));
+            send_events();
+        }
+    };
+
+    ($duration:expr, $max_size:expr $(, timer = $set_timer:expr)?) => {
+        ic_event_hub::implement_event_emitter!(
+            @state ic_event_hub::storage::HeapStorage,
+            ic_event_hub::event_hub::EventHub::new($duration, $max_size)
+        );
+        ic_event_hub::implement_event_emitter!(@schedule $($set_timer)?);
+
         pub fn _take_event_hub_state() -> Option<ic_event_hub::event_hub::EventHub> {
-            unsafe { _EVENT_HUB.take() }
+            _EVENT_HUB.with(|hub| hub.take())
         }
 
         pub fn _put_event_hub_state(state: Option<ic_event_hub::event_hub::EventHub>) {
-            unsafe { _EVENT_HUB = state }
+            _EVENT_HUB.with(|hub| hub.put(state));
+            _event_hub_schedule_delivery();
         }
 
-        pub fn emit(
-            event: impl ic_event_hub::types::IEvent,
-        ) -> Result<(), ic_event_hub::types::EventHubError> {
-            ic_event_hub::fns::emit_impl(event, get_event_hub())
+        pub fn _save_event_hub_state() -> ic_event_hub::upgrade::VersionedEventHubState {
+            ic_event_hub::upgrade::VersionedEventHubState::new(_take_event_hub_state())
         }
 
-        pub fn send_events() {
-            ic_event_hub::fns::send_events_impl(get_event_hub());
+        pub fn _restore_event_hub_state(state: ic_event_hub::upgrade::VersionedEventHubState) {
+            _put_event_hub_state(state.restore(ic_event_hub::upgrade::no_migration));
+        }
+    };
+
+    (
+        $duration:expr,
+        $max_size:expr,
+        upgrade = ($save:expr, $restore:expr)
+        $(, timer = $set_timer:expr)?
+    ) => {
+        ic_event_hub::implement_event_emitter!(
+            $duration,
+            $max_size,
+            upgrade = ($save, $restore),
+            migrate = ic_event_hub::upgrade::no_migration
+            $(, timer = $set_timer)?
+        );
+    };
+
+    (
+        $duration:expr,
+        $max_size:expr,
+        upgrade = ($save:expr, $restore:expr),
+        migrate = $migrate:expr
+        $(, timer = $set_timer:expr)?
+    ) => {
+        ic_event_hub::implement_event_emitter!($duration, $max_size $(, timer = $set_timer)?);
+
+        #[ic_cdk_macros::pre_upgrade]
+        fn _event_hub_pre_upgrade() {
+            let canister_state = ($save)();
+            let event_hub_state = _save_event_hub_state();
+
+            ic_cdk::storage::stable_save((canister_state, event_hub_state))
+                .expect("Unable to stable save");
+        }
+
+        #[ic_cdk_macros::post_upgrade]
+        fn _event_hub_post_upgrade() {
+            // only the event hub state is checked, so errors of the canister state are not hidden
+            let header: Result<
+                (
+                    ic_cdk::export::candid::Reserved,
+                    ic_event_hub::upgrade::VersionedEventHubState,
+                ),
+                _,
+            > = ic_cdk::storage::stable_restore();
+
+            // the state was saved before the event hub state was versioned
+            if header.is_err() {
+                let (canister_state, event_hub_state): (
+                    _,
+                    Option<ic_event_hub::upgrade::LegacyEventHub>,
+                ) = ic_cdk::storage::stable_restore().expect("Unable to stable restore");
+
+                ($restore)(canister_state);
+                _put_event_hub_state(event_hub_state.map(Into::into));
+
+                return;
+            }
+
+            let (canister_state, event_hub_state): (
+                _,
+                ic_event_hub::upgrade::VersionedEventHubState,
+            ) = ic_cdk::storage::stable_restore().expect("Unable to stable restore");
+
+            ($restore)(canister_state);
+            _put_event_hub_state(event_hub_state.restore($migrate));
+        }
+    };
+
+    (
+        $duration:expr,
+        $max_size:expr,
+        stable_storage = $storage:expr
+        $(, post_upgrade = $post_upgrade:expr)?
+        $(, timer = $set_timer:expr)?
+    ) => {
+        ic_event_hub::implement_event_emitter!(
+            @state ic_event_hub::stable_storage::StableStorage,
+            ic_event_hub::event_hub::EventHub::with_storage($duration, $max_size, $storage)
+        );
+        ic_event_hub::implement_event_emitter!(@schedule $($set_timer)?);
+
+        #[ic_cdk_macros::post_upgrade]
+        fn _event_hub_post_upgrade() {
+            $(($post_upgrade)();)?
+
+            // calls made before the upgrade will never complete
+            with_event_hub(|hub| hub.reset_in_flight());
+
+            // timers do not survive upgrades, while batches in stable memory do
+            _event_hub_schedule_delivery();
         }
     };
 }
@@ -38,15 +195,31 @@ macro_rules! implement_event_emitter {
 macro_rules! implement_subscribe {
     () => {
         #[ic_cdk_macros::update]
-        fn subscribe(req: ic_event_hub::types::SubscribeRequest) {
-            ic_event_hub::fns::subscribe_impl(req, get_event_hub());
+        #[ic_cdk::export::candid::candid_method(update)]
+        fn subscribe(
+            req: ic_event_hub::types::SubscribeRequest,
+        ) -> ic_event_hub::types::SubscribeResponse {
+            let res = with_event_hub(|hub| {
+                ic_event_hub::fns::subscribe_impl(req, hub, &ic_event_hub::runtime::IcRuntime)
+            });
+            _event_hub_schedule_delivery();
+
+            res
         }
     };
 
     (guard = $guard:expr) => {
         #[ic_cdk_macros::update(guard = $guard)]
-        fn subscribe(req: ic_event_hub::types::SubscribeRequest) {
-            ic_event_hub::fns::subscribe_impl(req, get_event_hub());
+        #[ic_cdk::export::candid::candid_method(update)]
+        fn subscribe(
+            req: ic_event_hub::types::SubscribeRequest,
+        ) -> ic_event_hub::types::SubscribeResponse {
+            let res = with_event_hub(|hub| {
+                ic_event_hub::fns::subscribe_impl(req, hub, &ic_event_hub::runtime::IcRuntime)
+            });
+            _event_hub_schedule_delivery();
+
+            res
         }
     };
 }
@@ -55,15 +228,213 @@ macro_rules! implement_subscribe {
 macro_rules! implement_unsubscribe {
     () => {
         #[ic_cdk_macros::update]
+        #[ic_cdk::export::candid::candid_method(update)]
         fn unsubscribe(req: ic_event_hub::types::UnsubscribeRequest) {
-            ic_event_hub::fns::unsubscribe_impl(req, get_event_hub());
+            with_event_hub(|hub| {
+                ic_event_hub::fns::unsubscribe_impl(req, hub, &ic_event_hub::runtime::IcRuntime)
+            });
+            _event_hub_schedule_delivery();
         }
     };
 
     (guard = $guard:expr) => {
         #[ic_cdk_macros::update(guard = $guard)]
+        #[ic_cdk::export::candid::candid_method(update)]
         fn unsubscribe(req: ic_event_hub::types::UnsubscribeRequest) {
-            ic_event_hub::fns::unsubscribe_impl(req, get_event_hub());
+            with_event_hub(|hub| {
+                ic_event_hub::fns::unsubscribe_impl(req, hub, &ic_event_hub::runtime::IcRuntime)
+            });
+            _event_hub_schedule_delivery();
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! implement_get_subscribers {
+    () => {
+        #[ic_cdk_macros::query]
+        #[ic_cdk::export::candid::candid_method(query)]
+        fn get_subscribers(
+            req: ic_event_hub::types::GetSubscribersRequest,
+        ) -> ic_event_hub::types::GetSubscribersResponse {
+            with_event_hub(|hub| ic_event_hub::fns::get_subscriers_impl(req, hub))
+        }
+    };
+
+    (guard = $guard:expr) => {
+        #[ic_cdk_macros::query(guard = $guard)]
+        #[ic_cdk::export::candid::candid_method(query)]
+        fn get_subscribers(
+            req: ic_event_hub::types::GetSubscribersRequest,
+        ) -> ic_event_hub::types::GetSubscribersResponse {
+            with_event_hub(|hub| ic_event_hub::fns::get_subscriers_impl(req, hub))
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! implement_subscription_epoch {
+    () => {
+        #[ic_cdk_macros::query]
+        #[ic_cdk::export::candid::candid_method(query)]
+        fn subscription_epoch() -> u64 {
+            with_event_hub(|hub| ic_event_hub::fns::subscription_epoch_impl(hub))
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! implement_relay {
+    () => {
+        #[ic_cdk_macros::update]
+        #[ic_cdk::export::candid::candid_method(update)]
+        fn subscribe_for(req: ic_event_hub::types::RelaySubscribeRequest) {
+            with_event_hub(|hub| {
+                ic_event_hub::fns::subscribe_for_impl(req, hub, &ic_event_hub::runtime::IcRuntime)
+            });
+            _event_hub_schedule_delivery();
+        }
+
+        #[ic_cdk_macros::update]
+        #[ic_cdk::export::candid::candid_method(update)]
+        fn unsubscribe_for(req: ic_event_hub::types::RelayUnsubscribeRequest) {
+            with_event_hub(|hub| {
+                ic_event_hub::fns::unsubscribe_for_impl(req, hub, &ic_event_hub::runtime::IcRuntime)
+            });
+            _event_hub_schedule_delivery();
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! implement_event_catalog {
+    ($($event:ty),* $(,)?) => {
+        #[ic_cdk_macros::query]
+        #[ic_cdk::export::candid::candid_method(query)]
+        fn get_event_catalog() -> Vec<ic_event_hub::types::EventSchema> {
+            with_event_hub(|hub| {
+                ic_event_hub::fns::get_event_catalog_impl(
+                    hub,
+                    vec![$(<$event as ic_event_hub::types::IEventSchema>::event_schema()),*],
+                )
+            })
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! implement_event_callback {
+    ($method_name:ident, $handler:expr) => {
+        #[ic_cdk_macros::update]
+        #[ic_cdk::export::candid::candid_method(update)]
+        fn $method_name(events: Vec<ic_event_hub::types::Event>) {
+            ic_event_hub::fns::check_event_sender(
+                stringify!($method_name),
+                &ic_event_hub::runtime::IcRuntime,
+            );
+            ic_event_hub::fns::check_event_emitters(stringify!($method_name), &events);
+
+            ($handler)(events);
+        }
+    };
+
+    ($method_name:ident, $handler:expr, ordered) => {
+        #[ic_cdk_macros::update]
+        #[ic_cdk::export::candid::candid_method(update)]
+        fn $method_name(events: Vec<ic_event_hub::types::Event>, seq: u64) {
+            ic_event_hub::fns::check_event_sender(
+                stringify!($method_name),
+                &ic_event_hub::runtime::IcRuntime,
+            );
+            ic_event_hub::fns::check_event_emitters(stringify!($method_name), &events);
+
+            ($handler)(events, seq);
+        }
+    };
+
+    ($method_name:ident, $handler:expr, envelope) => {
+        #[ic_cdk_macros::update]
+        #[ic_cdk::export::candid::candid_method(update)]
+        fn $method_name(batch: ic_event_hub::types::EventBatch) {
+            ic_event_hub::fns::check_event_sender(
+                stringify!($method_name),
+                &ic_event_hub::runtime::IcRuntime,
+            );
+            ic_event_hub::fns::check_event_emitters(stringify!($method_name), &batch.events);
+
+            ($handler)(batch);
+        }
+    };
+
+    ($method_name:ident, $handler:expr, compressed) => {
+        #[ic_cdk_macros::update]
+        #[ic_cdk::export::candid::candid_method(update)]
+        fn $method_name(batch: ic_event_hub::types::CompressedEventBatch) {
+            ic_event_hub::fns::check_event_sender(
+                stringify!($method_name),
+                &ic_event_hub::runtime::IcRuntime,
+            );
+
+            let events = batch
+                .decompress()
+                .unwrap_or_else(|e| ic_cdk::trap(e.as_str()));
+            ic_event_hub::fns::check_event_emitters(stringify!($method_name), &events);
+
+            ($handler)(events);
+        }
+    };
+
+    ($method_name:ident, $handler:expr, compact) => {
+        #[ic_cdk_macros::update]
+        #[ic_cdk::export::candid::candid_method(update)]
+        fn $method_name(events: Vec<ic_event_hub::types::CompactEvent>) {
+            ic_event_hub::fns::check_event_sender(
+                stringify!($method_name),
+                &ic_event_hub::runtime::IcRuntime,
+            );
+            ic_event_hub::fns::check_event_emitters(
+                stringify!($method_name),
+                events.iter().filter_map(|event| match event {
+                    ic_event_hub::types::CompactEvent::Full(event) => Some(event),
+                    _ => None,
+                }),
+            );
+
+            ($handler)(events);
+        }
+    };
+
+    ($method_name:ident, $handler:expr, allow_unknown_emitters) => {
+        #[ic_cdk_macros::update]
+        #[ic_cdk::export::candid::candid_method(update)]
+        fn $method_name(events: Vec<ic_event_hub::types::Event>) {
+            ($handler)(events);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! implement_event_listener {
+    (upgrade = ($save:expr, $restore:expr)) => {
+        #[ic_cdk_macros::pre_upgrade]
+        fn _event_listener_pre_upgrade() {
+            let canister_state = ($save)();
+            let registry_state =
+                ic_event_hub::subscription_registry::_take_subscription_registry_state();
+
+            ic_cdk::storage::stable_save((canister_state, registry_state))
+                .expect("Unable to stable save");
+        }
+
+        #[ic_cdk_macros::post_upgrade]
+        fn _event_listener_post_upgrade() {
+            let (canister_state, registry_state): (
+                _,
+                ic_event_hub::subscription_registry::SubscriptionRegistry,
+            ) = ic_cdk::storage::stable_restore().expect("Unable to stable restore");
+
+            ($restore)(canister_state);
+            ic_event_hub::subscription_registry::_put_subscription_registry_state(registry_state);
         }
     };
 }
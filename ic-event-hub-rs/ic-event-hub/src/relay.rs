@@ -0,0 +1,615 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::export::Principal;
+
+use crate::types::{CallbackInfo, EventField, RelaySubscribeRequest, RemoteCallEndpoint};
+use crate::{RELAY_SUBSCRIBE_METHOD, RELAY_UNSUBSCRIBE_METHOD};
+
+/// A change of relay subscriptions which the root emitter still has to send to the relay
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum RelayUpdate {
+    Subscribe {
+        relay: Principal,
+        request: RelaySubscribeRequest,
+    },
+    Unsubscribe {
+        relay: Principal,
+        request: RelaySubscribeRequest,
+    },
+}
+
+impl RelayUpdate {
+    pub fn relay(&self) -> Principal {
+        match self {
+            RelayUpdate::Subscribe { relay, .. } | RelayUpdate::Unsubscribe { relay, .. } => *relay,
+        }
+    }
+
+    pub fn method_name(&self) -> &'static str {
+        match self {
+            RelayUpdate::Subscribe { .. } => RELAY_SUBSCRIBE_METHOD,
+            RelayUpdate::Unsubscribe { .. } => RELAY_UNSUBSCRIBE_METHOD,
+        }
+    }
+
+    pub fn request(&self) -> &RelaySubscribeRequest {
+        match self {
+            RelayUpdate::Subscribe { request, .. } | RelayUpdate::Unsubscribe { request, .. } => {
+                request
+            }
+        }
+    }
+}
+
+/// A struct that shards listeners of a root emitter across relay canisters
+///
+/// Each relay is subscribed to the root with a catch-all filter and re-publishes events to its own
+/// subset of listeners, so the root makes one call per relay instead of one call per listener.
+/// Every listener is served by exactly one relay - the least loaded one at the moment of its first
+/// subscription. Subscription changes are queued as `RelayUpdate`s and are sent to relays along
+/// with the next event batches.
+///
+/// When subnets of canisters are known (see `set_subnet()`), listeners are only assigned to relays
+/// on their own subnet, if there are any. This way the root sends a single cross-subnet message per
+/// subnet and the rest of the fan-out is made of cheaper same-subnet calls.
+///
+/// On the relay side the same struct holds the set of upstream emitters, which are allowed to
+/// subscribe listeners on their behalf
+#[derive(Default, CandidType, Deserialize)]
+pub struct RelayRegistry {
+    pub(crate) relays: BTreeMap<Principal, BTreeSet<Principal>>,
+    pub(crate) assignments: BTreeMap<Principal, Principal>,
+    pub(crate) subscriptions: BTreeMap<Principal, BTreeSet<CallbackInfo>>,
+    pub(crate) pending_updates: VecDeque<RelayUpdate>,
+    pub(crate) upstreams: BTreeSet<Principal>,
+    pub(crate) subnets: BTreeMap<Principal, Principal>,
+    pub(crate) seeded: BTreeSet<Principal>,
+}
+
+impl RelayRegistry {
+    pub fn add_relay(&mut self, relay: Principal) {
+        self.relays.entry(relay).or_insert_with(BTreeSet::new);
+    }
+
+    /// Removes the relay and moves its listeners to other relays
+    ///
+    /// Returns listeners which could not be moved because there are no relays left, together with
+    /// their callbacks
+    pub fn remove_relay(&mut self, relay: &Principal) -> Vec<(Principal, Vec<CallbackInfo>)> {
+        let listeners = match self.relays.remove(relay) {
+            Some(listeners) => listeners,
+            None => return vec![],
+        };
+        self.seeded.remove(relay);
+
+        let mut orphans = vec![];
+
+        for listener in listeners {
+            self.assignments.remove(&listener);
+            let callbacks = self
+                .subscriptions
+                .remove(&listener)
+                .map(|callbacks| callbacks.into_iter().collect())
+                .unwrap_or_default();
+
+            if self.relays.is_empty() {
+                orphans.push((listener, callbacks));
+            } else {
+                self.subscribe(listener, callbacks);
+            }
+        }
+
+        orphans
+    }
+
+    /// Remembers the subnet a listener or a relay canister is on
+    ///
+    /// Already assigned listeners are not moved until `rebalance()` is called
+    pub fn set_subnet(&mut self, canister_id: Principal, subnet: Principal) {
+        self.subnets.insert(canister_id, subnet);
+    }
+
+    pub fn get_subnet(&self, canister_id: &Principal) -> Option<Principal> {
+        self.subnets.get(canister_id).cloned()
+    }
+
+    pub fn has_relays(&self) -> bool {
+        !self.relays.is_empty()
+    }
+
+    pub fn is_relay(&self, canister_id: &Principal) -> bool {
+        self.relays.contains_key(canister_id)
+    }
+
+    /// Marks the relay as the one which has received retained events of the upstream. Returns
+    /// `false` if it was marked already.
+    pub(crate) fn mark_seeded(&mut self, relay: Principal) -> bool {
+        self.seeded.insert(relay)
+    }
+
+    pub fn get_relays(&self) -> Vec<Principal> {
+        self.relays.keys().cloned().collect()
+    }
+
+    pub fn get_relay_of(&self, listener: &Principal) -> Option<Principal> {
+        self.assignments.get(listener).cloned()
+    }
+
+    /// Returns the number of listeners served by the relay
+    pub fn get_load(&self, relay: &Principal) -> usize {
+        self.relays
+            .get(relay)
+            .map(|it| it.len())
+            .unwrap_or_default()
+    }
+
+    /// Subscribes the listener via its relay, assigning one if it has none yet. Returns the relay
+    /// or `None` if there are no relays at all.
+    pub fn subscribe(
+        &mut self,
+        listener: Principal,
+        callbacks: Vec<CallbackInfo>,
+    ) -> Option<Principal> {
+        let relay = match self.get_relay_of(&listener) {
+            Some(relay) => relay,
+            None => {
+                let relay = self.least_loaded_relay(&self.candidate_relays(&listener))?;
+
+                self.assignments.insert(listener, relay);
+                self.relays.get_mut(&relay).unwrap().insert(listener);
+
+                relay
+            }
+        };
+
+        let subscriptions = self
+            .subscriptions
+            .entry(listener)
+            .or_insert_with(BTreeSet::new);
+
+        // a callback is identified by its filter and method, the latest subscription sets its format
+        for callback in callbacks.iter().cloned().map(canonical_callback) {
+            subscriptions.retain(|it| !same_callback(it, &callback));
+            subscriptions.insert(callback);
+        }
+
+        self.pending_updates.push_back(RelayUpdate::Subscribe {
+            relay,
+            request: RelaySubscribeRequest {
+                listener,
+                callbacks,
+            },
+        });
+
+        Some(relay)
+    }
+
+    /// Unsubscribes the listener via its relay. The listener is unassigned from the relay once it
+    /// has no callbacks left.
+    pub fn unsubscribe(&mut self, listener: Principal, callbacks: Vec<CallbackInfo>) {
+        let relay = match self.get_relay_of(&listener) {
+            Some(relay) => relay,
+            None => return,
+        };
+
+        if let Some(subscriptions) = self.subscriptions.get_mut(&listener) {
+            for callback in callbacks.iter().cloned().map(canonical_callback) {
+                subscriptions.retain(|it| !same_callback(it, &callback));
+            }
+
+            if subscriptions.is_empty() {
+                self.subscriptions.remove(&listener);
+                self.assignments.remove(&listener);
+                self.relays.get_mut(&relay).unwrap().remove(&listener);
+            }
+        }
+
+        self.pending_updates.push_back(RelayUpdate::Unsubscribe {
+            relay,
+            request: RelaySubscribeRequest {
+                listener,
+                callbacks,
+            },
+        });
+    }
+
+    /// Moves listeners served by a relay on another subnet to relays on their own subnet, then
+    /// moves listeners from the most loaded relays to the least loaded ones of the same subnet,
+    /// until their loads differ by at most one. Returns the number of moved listeners.
+    ///
+    /// A moved listener is subscribed via its new relay before it is unsubscribed from the old
+    /// one, so it could receive some events twice, but never misses any
+    pub fn rebalance(&mut self) -> usize {
+        let mut moved = 0;
+
+        let misplaced: Vec<_> = self
+            .assignments
+            .iter()
+            .filter(|(listener, relay)| !self.candidate_relays(listener).contains(relay))
+            .map(|(listener, relay)| (*listener, *relay))
+            .collect();
+
+        for (listener, from) in misplaced {
+            let to = self
+                .least_loaded_relay(&self.candidate_relays(&listener))
+                .unwrap();
+
+            self.move_listener(listener, from, to);
+            moved += 1;
+        }
+
+        let subnets: BTreeSet<_> = self
+            .relays
+            .keys()
+            .map(|relay| self.get_subnet(relay))
+            .collect();
+
+        for subnet in subnets {
+            let group = self.relays_on_subnet(subnet);
+
+            loop {
+                let (busiest, idlest) = match (
+                    self.most_loaded_relay(&group),
+                    self.least_loaded_relay(&group),
+                ) {
+                    (Some(busiest), Some(idlest)) => (busiest, idlest),
+                    _ => break,
+                };
+
+                if self.get_load(&busiest) <= self.get_load(&idlest) + 1 {
+                    break;
+                }
+
+                let listener = *self.relays[&busiest].iter().next_back().unwrap();
+
+                self.move_listener(listener, busiest, idlest);
+                moved += 1;
+            }
+        }
+
+        moved
+    }
+
+    pub fn add_upstream(&mut self, upstream: Principal) {
+        self.upstreams.insert(upstream);
+    }
+
+    pub fn is_upstream(&self, canister_id: &Principal) -> bool {
+        self.upstreams.contains(canister_id)
+    }
+
+    pub(crate) fn has_pending_updates(&self) -> bool {
+        !self.pending_updates.is_empty()
+    }
+
+    pub(crate) fn take_pending_updates(&mut self) -> Vec<RelayUpdate> {
+        self.pending_updates.drain(..).collect()
+    }
+
+    /// Puts updates which were not sent back to the front of the queue, keeping their order
+    pub(crate) fn requeue_updates(&mut self, updates: Vec<RelayUpdate>) {
+        for update in updates.into_iter().rev() {
+            self.pending_updates.push_front(update);
+        }
+    }
+
+    /// Re-encodes filters of relayed callbacks canonically. Used to migrate a state saved by a
+    /// version which stored filters the way they were subscribed with.
+    pub(crate) fn canonicalize_filters(&mut self) {
+        for subscriptions in self.subscriptions.values_mut() {
+            *subscriptions = std::mem::take(subscriptions)
+                .into_iter()
+                .map(canonical_callback)
+                .collect();
+        }
+    }
+
+    /// Returns callbacks of relayed listeners, whose filters are subsets of the (canonical) topics
+    pub fn match_listeners(&self, topics: &BTreeSet<EventField>) -> Vec<RemoteCallEndpoint> {
+        self.subscriptions
+            .iter()
+            .flat_map(|(listener, callbacks)| {
+                callbacks
+                    .iter()
+                    .filter(|callback| callback.filter.0.is_subset(topics))
+                    .map(move |callback| RemoteCallEndpoint {
+                        canister_id: *listener,
+                        method_name: callback.method_name.clone(),
+                    })
+            })
+            .collect()
+    }
+
+    fn move_listener(&mut self, listener: Principal, from: Principal, to: Principal) {
+        let callbacks: Vec<_> = self.subscriptions[&listener].iter().cloned().collect();
+
+        self.relays.get_mut(&from).unwrap().remove(&listener);
+        self.relays.get_mut(&to).unwrap().insert(listener);
+        self.assignments.insert(listener, to);
+
+        self.pending_updates.push_back(RelayUpdate::Subscribe {
+            relay: to,
+            request: RelaySubscribeRequest {
+                listener,
+                callbacks: callbacks.clone(),
+            },
+        });
+        self.pending_updates.push_back(RelayUpdate::Unsubscribe {
+            relay: from,
+            request: RelaySubscribeRequest {
+                listener,
+                callbacks,
+            },
+        });
+    }
+
+    /// Relays on the subnet of the listener, or all the relays if there are none on its subnet
+    fn candidate_relays(&self, listener: &Principal) -> Vec<Principal> {
+        if let Some(subnet) = self.get_subnet(listener) {
+            let local = self.relays_on_subnet(Some(subnet));
+
+            if !local.is_empty() {
+                return local;
+            }
+        }
+
+        self.get_relays()
+    }
+
+    fn relays_on_subnet(&self, subnet: Option<Principal>) -> Vec<Principal> {
+        self.relays
+            .keys()
+            .filter(|relay| self.get_subnet(relay) == subnet)
+            .cloned()
+            .collect()
+    }
+
+    fn least_loaded_relay(&self, candidates: &[Principal]) -> Option<Principal> {
+        candidates
+            .iter()
+            .min_by_key(|relay| (self.get_load(relay), **relay))
+            .cloned()
+    }
+
+    fn most_loaded_relay(&self, candidates: &[Principal]) -> Option<Principal> {
+        candidates
+            .iter()
+            .max_by_key(|relay| (self.get_load(relay), std::cmp::Reverse(**relay)))
+            .cloned()
+    }
+}
+
+/// Relayed callbacks are stored with canonical filters, so they are matched without re-encoding
+fn canonical_callback(callback: CallbackInfo) -> CallbackInfo {
+    CallbackInfo {
+        filter: callback.filter.canonicalize(),
+        ..callback
+    }
+}
+
+/// Whether both callbacks are of the same filter and method, whatever their formats are
+fn same_callback(a: &CallbackInfo, b: &CallbackInfo) -> bool {
+    a.filter == b.filter && a.method_name == b.method_name
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::relay::{RelayRegistry, RelayUpdate};
+    use crate::types::{BatchFormat, CallbackInfo, EventField, EventFilter, RemoteCallEndpoint};
+    use candid::{encode_one, Principal};
+    use std::collections::BTreeSet;
+
+    fn callback(method_name: &str) -> CallbackInfo {
+        CallbackInfo {
+            filter: EventFilter::empty(),
+            method_name: String::from(method_name),
+            format: None,
+        }
+    }
+
+    #[test]
+    fn listeners_are_sharded_and_rebalanced() {
+        let mut registry = RelayRegistry::default();
+        let relay_1 = Principal::from_slice(&[1]);
+        let relay_2 = Principal::from_slice(&[2]);
+
+        assert!(registry
+            .subscribe(Principal::from_slice(&[10]), vec![callback("a")])
+            .is_none());
+
+        registry.add_relay(relay_1);
+        registry.add_relay(relay_2);
+
+        for i in 10..16 {
+            registry.subscribe(Principal::from_slice(&[i]), vec![callback("a")]);
+        }
+
+        assert_eq!(registry.get_load(&relay_1), 3);
+        assert_eq!(registry.get_load(&relay_2), 3);
+
+        // subsequent subscriptions of the same listener stay on its relay
+        let relay = registry
+            .get_relay_of(&Principal::from_slice(&[10]))
+            .unwrap();
+        registry.subscribe(Principal::from_slice(&[10]), vec![callback("b")]);
+        assert_eq!(registry.get_load(&relay), 3);
+
+        assert_eq!(registry.take_pending_updates().len(), 7);
+
+        let orphans = registry.remove_relay(&relay_1);
+        assert!(orphans.is_empty());
+        assert_eq!(registry.get_load(&relay_2), 6);
+
+        let updates = registry.take_pending_updates();
+        assert_eq!(updates.len(), 3);
+        assert!(updates.iter().all(|update| update.relay() == relay_2));
+
+        let relay_3 = Principal::from_slice(&[3]);
+        registry.add_relay(relay_3);
+        assert_eq!(registry.rebalance(), 3);
+        assert_eq!(registry.get_load(&relay_2), 3);
+        assert_eq!(registry.get_load(&relay_3), 3);
+
+        let updates = registry.take_pending_updates();
+        assert_eq!(updates.len(), 6);
+        assert!(matches!(updates[0], RelayUpdate::Subscribe { relay, .. } if relay == relay_3));
+        assert!(matches!(updates[1], RelayUpdate::Unsubscribe { relay, .. } if relay == relay_2));
+
+        let listener = updates[0].request().listener;
+        registry.unsubscribe(listener, vec![callback("a")]);
+        assert!(registry.get_relay_of(&listener).is_none());
+        assert_eq!(registry.get_load(&relay_3), 2);
+
+        registry.remove_relay(&relay_2);
+        let orphans = registry.remove_relay(&relay_3);
+        assert_eq!(orphans.len(), 5);
+    }
+
+    #[test]
+    fn listeners_are_routed_to_relays_on_their_subnet() {
+        let mut registry = RelayRegistry::default();
+        let subnet_a = Principal::from_slice(&[100]);
+        let subnet_b = Principal::from_slice(&[101]);
+
+        let relay_a = Principal::from_slice(&[1]);
+        registry.set_subnet(relay_a, subnet_a);
+        registry.add_relay(relay_a);
+
+        // listeners on subnet b fall back to any relay while there is no relay on their subnet
+        for i in 10..14 {
+            let listener = Principal::from_slice(&[i]);
+            registry.set_subnet(listener, if i % 2 == 0 { subnet_a } else { subnet_b });
+
+            assert_eq!(
+                registry.subscribe(listener, vec![callback("a")]),
+                Some(relay_a)
+            );
+        }
+
+        let relay_b_1 = Principal::from_slice(&[2]);
+        let relay_b_2 = Principal::from_slice(&[3]);
+        for relay in [relay_b_1, relay_b_2] {
+            registry.set_subnet(relay, subnet_b);
+            registry.add_relay(relay);
+        }
+
+        let listener = Principal::from_slice(&[14]);
+        registry.set_subnet(listener, subnet_a);
+        assert_eq!(
+            registry.subscribe(listener, vec![callback("a")]),
+            Some(relay_a)
+        );
+
+        // two misplaced listeners of subnet b are moved, one per relay, and relay a is not touched
+        registry.take_pending_updates();
+        assert_eq!(registry.rebalance(), 2);
+        assert_eq!(registry.get_load(&relay_a), 3);
+        assert_eq!(registry.get_load(&relay_b_1), 1);
+        assert_eq!(registry.get_load(&relay_b_2), 1);
+
+        let updates = registry.take_pending_updates();
+        assert!(updates
+            .iter()
+            .all(|update| registry.get_subnet(&update.request().listener) == Some(subnet_b)));
+    }
+
+    #[test]
+    fn unsent_updates_are_requeued_in_order() {
+        let mut registry = RelayRegistry::default();
+        let relay = Principal::from_slice(&[1]);
+        registry.add_relay(relay);
+
+        for i in 10..14 {
+            registry.subscribe(Principal::from_slice(&[i]), vec![callback("a")]);
+        }
+
+        let mut updates = registry.take_pending_updates();
+        let rest = updates.split_off(2);
+        registry.subscribe(Principal::from_slice(&[14]), vec![callback("a")]);
+        registry.requeue_updates(rest);
+
+        let listeners: Vec<_> = registry
+            .take_pending_updates()
+            .iter()
+            .map(|update| update.request().listener)
+            .collect();
+        assert_eq!(
+            listeners,
+            vec![
+                Principal::from_slice(&[12]),
+                Principal::from_slice(&[13]),
+                Principal::from_slice(&[14])
+            ]
+        );
+    }
+
+    #[test]
+    fn relayed_listeners_are_matched() {
+        let mut registry = RelayRegistry::default();
+        registry.add_relay(Principal::from_slice(&[1]));
+
+        let listener = Principal::from_slice(&[10]);
+        let topic = EventField {
+            name: String::from("kind"),
+            value: encode_one(5u32).unwrap(),
+        };
+
+        let mut topics = BTreeSet::new();
+        topics.insert(topic.clone());
+
+        let mut narrow = callback("narrow");
+        narrow.filter = EventFilter(topics.clone());
+        registry.subscribe(listener, vec![callback("all"), narrow]);
+
+        let all = RemoteCallEndpoint {
+            canister_id: listener,
+            method_name: String::from("all"),
+        };
+        let narrow = RemoteCallEndpoint {
+            canister_id: listener,
+            method_name: String::from("narrow"),
+        };
+
+        assert_eq!(
+            registry.match_listeners(&BTreeSet::new()),
+            vec![all.clone()]
+        );
+
+        let matched = registry.match_listeners(&EventFilter(topics).canonicalize().0);
+        assert_eq!(matched.len(), 2);
+        assert!(matched.contains(&all) && matched.contains(&narrow));
+    }
+
+    #[test]
+    fn relayed_callbacks_are_unsubscribed_by_canonical_filter() {
+        let mut registry = RelayRegistry::default();
+        let relay = Principal::from_slice(&[1]);
+        registry.add_relay(relay);
+
+        let listener = Principal::from_slice(&[10]);
+        let filter = |value| {
+            EventFilter(
+                vec![EventField {
+                    name: String::from("kind"),
+                    value,
+                }]
+                .into_iter()
+                .collect(),
+            )
+        };
+
+        let mut subscribed = callback("a");
+        subscribed.filter = filter(encode_one(5u32).unwrap());
+        subscribed.format = Some(BatchFormat::Compact);
+        registry.subscribe(listener, vec![subscribed]);
+
+        // the same filter encoded with another integer type and without a format
+        let mut unsubscribed = callback("a");
+        unsubscribed.filter = filter(encode_one(5u64).unwrap());
+        registry.unsubscribe(listener, vec![unsubscribed]);
+
+        assert!(registry.get_relay_of(&listener).is_none());
+        assert_eq!(registry.get_load(&relay), 0);
+    }
+}
@@ -0,0 +1,171 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use futures::future;
+use ic_cdk::api::call::{call_raw, CallResult, RejectionCode};
+use ic_cdk::export::Principal;
+
+use crate::types::RemoteCallEndpoint;
+
+pub type RawCallFuture = Pin<Box<dyn Future<Output = CallResult<Vec<u8>>>>>;
+
+/// Everything the event-hub needs from the system it is running on
+///
+/// `IcRuntime` is used inside canisters, `MockRuntime` allows to run the whole
+/// emit -> batch -> deliver flow in `cargo test` with simulated time and failures
+pub trait Runtime {
+    fn time(&self) -> u64;
+    fn caller(&self) -> Principal;
+    fn id(&self) -> Principal;
+    fn call_raw(&self, canister_id: Principal, method_name: &str, args: Vec<u8>) -> RawCallFuture;
+    fn log(&self, message: String);
+}
+
+/// Runtime of a real canister, backed by `ic_cdk` system calls
+#[derive(Clone, Copy, Default)]
+pub struct IcRuntime;
+
+impl Runtime for IcRuntime {
+    fn time(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+
+    fn caller(&self) -> Principal {
+        ic_cdk::caller()
+    }
+
+    fn id(&self) -> Principal {
+        ic_cdk::id()
+    }
+
+    fn call_raw(&self, canister_id: Principal, method_name: &str, args: Vec<u8>) -> RawCallFuture {
+        let method_name = String::from(method_name);
+
+        Box::pin(async move { call_raw(canister_id, method_name.as_str(), args, 0).await })
+    }
+
+    fn log(&self, message: String) {
+        ic_cdk::print(message);
+    }
+}
+
+/// A call made through `MockRuntime`
+#[derive(Clone, Debug)]
+pub struct MockCall {
+    pub canister_id: Principal,
+    pub method_name: String,
+    pub args: Vec<u8>,
+}
+
+struct MockRuntimeState {
+    time: u64,
+    caller: Principal,
+    id: Principal,
+    rejections: HashMap<RemoteCallEndpoint, (RejectionCode, String)>,
+    calls: Vec<MockCall>,
+    logs: Vec<String>,
+}
+
+/// In-memory runtime for tests
+///
+/// Clones share the same state, so a test could keep one copy to control time and inspect calls
+/// while another one is used by the event-hub. Calls are accepted unless rejected with
+/// `reject_calls_to()`.
+#[derive(Clone)]
+pub struct MockRuntime {
+    state: Rc<RefCell<MockRuntimeState>>,
+}
+
+impl MockRuntime {
+    pub fn new(id: Principal) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(MockRuntimeState {
+                time: 0,
+                caller: Principal::anonymous(),
+                id,
+                rejections: HashMap::new(),
+                calls: vec![],
+                logs: vec![],
+            })),
+        }
+    }
+
+    pub fn set_time(&self, time: u64) {
+        self.state.borrow_mut().time = time;
+    }
+
+    pub fn advance_time(&self, delta: u64) {
+        self.state.borrow_mut().time += delta;
+    }
+
+    pub fn set_caller(&self, caller: Principal) {
+        self.state.borrow_mut().caller = caller;
+    }
+
+    pub fn reject_calls_to(
+        &self,
+        endpoint: RemoteCallEndpoint,
+        code: RejectionCode,
+        message: &str,
+    ) {
+        self.state
+            .borrow_mut()
+            .rejections
+            .insert(endpoint, (code, String::from(message)));
+    }
+
+    pub fn accept_calls_to(&self, endpoint: &RemoteCallEndpoint) {
+        self.state.borrow_mut().rejections.remove(endpoint);
+    }
+
+    /// Returns all the calls made since the previous invocation, including the rejected ones
+    pub fn take_calls(&self) -> Vec<MockCall> {
+        std::mem::take(&mut self.state.borrow_mut().calls)
+    }
+
+    pub fn get_logs(&self) -> Vec<String> {
+        self.state.borrow().logs.clone()
+    }
+}
+
+impl Runtime for MockRuntime {
+    fn time(&self) -> u64 {
+        self.state.borrow().time
+    }
+
+    fn caller(&self) -> Principal {
+        self.state.borrow().caller
+    }
+
+    fn id(&self) -> Principal {
+        self.state.borrow().id
+    }
+
+    fn call_raw(&self, canister_id: Principal, method_name: &str, args: Vec<u8>) -> RawCallFuture {
+        let mut state = self.state.borrow_mut();
+
+        let endpoint = RemoteCallEndpoint {
+            canister_id,
+            method_name: String::from(method_name),
+        };
+        let result = match state.rejections.get(&endpoint) {
+            Some((code, message)) => Err((*code, message.clone())),
+            None => Ok(candid::encode_args(()).expect("Unable to encode reply")),
+        };
+
+        state.calls.push(MockCall {
+            canister_id,
+            method_name: endpoint.method_name,
+            args,
+        });
+
+        Box::pin(future::ready(result))
+    }
+
+    fn log(&self, message: String) {
+        self.state.borrow_mut().logs.push(message);
+    }
+}
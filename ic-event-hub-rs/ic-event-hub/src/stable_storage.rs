@@ -0,0 +1,648 @@
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+
+use candid::{decode_one, encode_one, CandidType, Deserialize};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
+
+use crate::storage::{EventHubStorage, PendingBatchMeta};
+use crate::types::{
+    BatchFormat, EncodedEventBatch, Event, EventField, EventFilter, EventSchema,
+    RemoteCallEndpoint, TimestampedRemoteCallEndpoint,
+};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+type Blob = Vec<u8>;
+
+/// How many virtual memories (starting from `first_memory_id`) are occupied by `StableStorage`
+pub const STABLE_STORAGE_MEMORIES_COUNT: u8 = 15;
+
+#[derive(Default, CandidType, Deserialize)]
+struct StableMeta {
+    epoch: u64,
+    next_ready_batch_idx: u64,
+    emitted_events: u64,
+    next_logged_idx: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct StablePendingBatch {
+    // the content of this batch is always empty, it is stored in chunks
+    batch: EncodedEventBatch,
+    size_bytes: usize,
+    chunks_count: u64,
+}
+
+/// `EventHub` storage that keeps subscriptions, batches and the event log directly in stable
+/// memory, so nothing has to be serialized on upgrade
+///
+/// Usage:
+/// ```ignore
+/// let storage = MEMORY_MANAGER.with(|m| StableStorage::init(&m.borrow(), 10));
+/// let hub = EventHub::with_storage(duration, max_size, storage);
+/// ```
+pub struct StableStorage {
+    // len(first topic of filter) ++ first topic of filter ++ len(filter) ++ filter ++ endpoint -> ()
+    //
+    // a listener is only matched against events having the first topic of its filter, listeners
+    // with empty filters are kept under an empty topic and are matched against every event
+    listeners: StableBTreeMap<Blob, Blob, Memory>,
+    // endpoint -> StablePendingBatch
+    pending_batches: StableBTreeMap<Blob, Blob, Memory>,
+    // len(endpoint) ++ endpoint ++ chunk idx -> content
+    pending_batch_chunks: StableBTreeMap<Blob, Blob, Memory>,
+    // timestamp ++ endpoint -> ()
+    pending_batch_queue: StableBTreeMap<Blob, Blob, Memory>,
+    // len(endpoint) ++ endpoint ++ batch idx -> EncodedEventBatch
+    ready_batches: StableBTreeMap<Blob, Blob, Memory>,
+    // idx -> Event
+    event_log: StableBTreeMap<Blob, Blob, Memory>,
+    // compaction key -> idx of the latest logged event with this key
+    latest_logged: StableBTreeMap<Blob, Blob, Memory>,
+    meta: StableCell<Blob, Memory>,
+    // endpoint -> u64
+    retry_deadlines: StableBTreeMap<Blob, Blob, Memory>,
+    // endpoint -> u64
+    batch_seqs: StableBTreeMap<Blob, Blob, Memory>,
+    // endpoint -> EncodedEventBatch
+    in_flight: StableBTreeMap<Blob, Blob, Memory>,
+    // endpoint -> u64
+    event_seqs: StableBTreeMap<Blob, Blob, Memory>,
+    // endpoint -> BatchFormat
+    batch_formats: StableBTreeMap<Blob, Blob, Memory>,
+    // event name -> EventSchema
+    event_schemas: StableBTreeMap<Blob, Blob, Memory>,
+    // topics -> Event
+    retained_events: StableBTreeMap<Blob, Blob, Memory>,
+}
+
+impl StableStorage {
+    /// Initializes the storage (or restores it after an upgrade) using virtual memories
+    /// `first_memory_id..first_memory_id + STABLE_STORAGE_MEMORIES_COUNT` of `memory_manager`
+    pub fn init(memory_manager: &MemoryManager<DefaultMemoryImpl>, first_memory_id: u8) -> Self {
+        let memory = |idx: u8| memory_manager.get(MemoryId::new(first_memory_id + idx));
+
+        Self {
+            listeners: StableBTreeMap::init(memory(0)),
+            pending_batches: StableBTreeMap::init(memory(1)),
+            pending_batch_chunks: StableBTreeMap::init(memory(2)),
+            pending_batch_queue: StableBTreeMap::init(memory(3)),
+            ready_batches: StableBTreeMap::init(memory(4)),
+            event_log: StableBTreeMap::init(memory(5)),
+            latest_logged: StableBTreeMap::init(memory(6)),
+            meta: StableCell::init(memory(7), encode(&StableMeta::default()))
+                .expect("Unable to init stable meta"),
+            retry_deadlines: StableBTreeMap::init(memory(8)),
+            batch_seqs: StableBTreeMap::init(memory(9)),
+            in_flight: StableBTreeMap::init(memory(10)),
+            event_seqs: StableBTreeMap::init(memory(11)),
+            batch_formats: StableBTreeMap::init(memory(12)),
+            event_schemas: StableBTreeMap::init(memory(13)),
+            retained_events: StableBTreeMap::init(memory(14)),
+        }
+    }
+
+    fn get_meta(&self) -> StableMeta {
+        decode(self.meta.get())
+    }
+
+    fn set_meta(&mut self, meta: StableMeta) {
+        self.meta
+            .set(encode(&meta))
+            .expect("Unable to save stable meta");
+    }
+
+    fn get_pending_batch(&self, endpoint: &RemoteCallEndpoint) -> Option<StablePendingBatch> {
+        self.pending_batches
+            .get(&encode(endpoint))
+            .map(|it| decode(&it))
+    }
+}
+
+impl EventHubStorage for StableStorage {
+    fn add_listener(&mut self, filter: EventFilter, endpoint: RemoteCallEndpoint) {
+        self.listeners
+            .insert(listener_key(&filter, &endpoint), Blob::new());
+    }
+
+    fn remove_listener(
+        &mut self,
+        filter: &EventFilter,
+        endpoint: &RemoteCallEndpoint,
+    ) -> Result<(), String> {
+        if self
+            .listeners
+            .remove(&listener_key(filter, endpoint))
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        let mut filter_prefix = index_prefix(filter);
+        filter_prefix.extend_from_slice(&prefixed(&encode(filter)));
+
+        let filter_exists = self
+            .listeners
+            .range(filter_prefix.clone()..)
+            .next()
+            .map(|(key, _)| key.starts_with(&filter_prefix))
+            .unwrap_or(false);
+
+        if filter_exists {
+            Err(String::from("No such listener in that filter"))
+        } else {
+            Err(String::from("No such filter"))
+        }
+    }
+
+    fn match_listeners(&self, topics: &BTreeSet<EventField>) -> Vec<RemoteCallEndpoint> {
+        let prefixes = std::iter::once(prefixed(&[]))
+            .chain(topics.iter().map(|topic| prefixed(&encode(topic))));
+
+        let mut listeners = vec![];
+
+        for prefix in prefixes {
+            for (key, _) in self
+                .listeners
+                .range(prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&prefix))
+            {
+                let (filter, endpoint) = split_prefixed(&key[prefix.len()..]);
+
+                if decode::<EventFilter>(filter).0.is_subset(topics) {
+                    listeners.push(decode(endpoint));
+                }
+            }
+        }
+
+        listeners
+    }
+
+    fn get_pending_batch_meta(&self, endpoint: &RemoteCallEndpoint) -> Option<PendingBatchMeta> {
+        self.get_pending_batch(endpoint)
+            .map(|pending| PendingBatchMeta {
+                timestamp: pending.batch.timestamp,
+                size_bytes: pending.size_bytes,
+                compact: pending.batch.compact,
+            })
+    }
+
+    fn start_pending_batch(&mut self, endpoint: RemoteCallEndpoint, mut batch: EncodedEventBatch) {
+        let content = std::mem::take(&mut batch.content);
+        let pending = StablePendingBatch {
+            batch,
+            size_bytes: content.len(),
+            chunks_count: 1,
+        };
+
+        let endpoint = encode(&endpoint);
+        self.pending_batch_chunks
+            .insert(indexed(&prefixed(&endpoint), 0), content);
+        self.pending_batches.insert(endpoint, encode(&pending));
+    }
+
+    fn append_to_pending_batch(&mut self, endpoint: &RemoteCallEndpoint, content: &[u8]) {
+        let mut pending = match self.get_pending_batch(endpoint) {
+            Some(it) => it,
+            None => return,
+        };
+
+        let endpoint = encode(endpoint);
+        self.pending_batch_chunks.insert(
+            indexed(&prefixed(&endpoint), pending.chunks_count),
+            Blob::from(content),
+        );
+
+        pending.batch.events_count += 1;
+        pending.size_bytes += content.len();
+        pending.chunks_count += 1;
+
+        self.pending_batches.insert(endpoint, encode(&pending));
+    }
+
+    fn take_pending_batch(&mut self, endpoint: &RemoteCallEndpoint) -> Option<EncodedEventBatch> {
+        let endpoint = encode(endpoint);
+        let mut pending: StablePendingBatch = decode(&self.pending_batches.remove(&endpoint)?);
+
+        let endpoint_prefix = prefixed(&endpoint);
+        for idx in 0..pending.chunks_count {
+            let chunk = self
+                .pending_batch_chunks
+                .remove(&indexed(&endpoint_prefix, idx))
+                .expect("Pending batch chunk is missing");
+
+            pending.batch.content.extend_from_slice(&chunk);
+        }
+
+        Some(pending.batch)
+    }
+
+    fn push_to_queue(&mut self, entry: TimestampedRemoteCallEndpoint) {
+        let mut key = Blob::from(entry.timestamp.to_be_bytes());
+        key.extend_from_slice(&encode(&entry.endpoint));
+
+        self.pending_batch_queue.insert(key, Blob::new());
+    }
+
+    fn peek_queue(&self) -> Option<TimestampedRemoteCallEndpoint> {
+        let (key, _) = self.pending_batch_queue.iter().next()?;
+
+        Some(TimestampedRemoteCallEndpoint {
+            timestamp: u64::from_be_bytes(key[..8].try_into().unwrap()),
+            endpoint: decode(&key[8..]),
+        })
+    }
+
+    fn pop_queue(&mut self) -> Option<TimestampedRemoteCallEndpoint> {
+        let (key, _) = self.pending_batch_queue.iter().next()?;
+        self.pending_batch_queue.remove(&key);
+
+        Some(TimestampedRemoteCallEndpoint {
+            timestamp: u64::from_be_bytes(key[..8].try_into().unwrap()),
+            endpoint: decode(&key[8..]),
+        })
+    }
+
+    fn push_ready_batch(&mut self, endpoint: RemoteCallEndpoint, batch: EncodedEventBatch) {
+        let mut meta = self.get_meta();
+        let key = indexed(&prefixed(&encode(&endpoint)), meta.next_ready_batch_idx);
+
+        self.ready_batches.insert(key, encode(&batch));
+
+        meta.next_ready_batch_idx += 1;
+        self.set_meta(meta);
+    }
+
+    fn pop_ready_batches(&mut self) -> Option<(RemoteCallEndpoint, Vec<EncodedEventBatch>)> {
+        let (first_key, _) = self.ready_batches.iter().next()?;
+        let (endpoint, _) = split_prefixed(&first_key);
+        let endpoint_prefix = prefixed(endpoint);
+
+        let entries: Vec<(Blob, Blob)> = self
+            .ready_batches
+            .range(endpoint_prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&endpoint_prefix))
+            .collect();
+
+        let mut batches = vec![];
+        for (key, batch) in entries {
+            self.ready_batches.remove(&key);
+            batches.push(decode(&batch));
+        }
+
+        Some((decode(endpoint), batches))
+    }
+
+    fn has_ready_batches(&self) -> bool {
+        !self.ready_batches.is_empty()
+    }
+
+    fn get_ready_endpoints(&self) -> BTreeSet<RemoteCallEndpoint> {
+        self.ready_batches
+            .iter()
+            .map(|(key, _)| decode(split_prefixed(&key).0))
+            .collect()
+    }
+
+    fn get_retry_deadline(&self, endpoint: &RemoteCallEndpoint) -> Option<u64> {
+        self.retry_deadlines
+            .get(&encode(endpoint))
+            .map(|it| u64::from_be_bytes(it[..].try_into().unwrap()))
+    }
+
+    fn set_retry_deadline(&mut self, endpoint: &RemoteCallEndpoint, deadline: Option<u64>) {
+        match deadline {
+            Some(deadline) => self
+                .retry_deadlines
+                .insert(encode(endpoint), Blob::from(deadline.to_be_bytes())),
+            None => self.retry_deadlines.remove(&encode(endpoint)),
+        };
+    }
+
+    fn next_batch_seq(&mut self, endpoint: &RemoteCallEndpoint) -> u64 {
+        next_counter(&mut self.batch_seqs, encode(endpoint), 1)
+    }
+
+    fn next_event_seq(&mut self, endpoint: &RemoteCallEndpoint, events_count: usize) -> u64 {
+        next_counter(&mut self.event_seqs, encode(endpoint), events_count as u64)
+    }
+
+    fn is_in_flight(&self, endpoint: &RemoteCallEndpoint) -> bool {
+        self.in_flight.contains_key(&encode(endpoint))
+    }
+
+    fn set_in_flight(&mut self, endpoint: RemoteCallEndpoint, batch: EncodedEventBatch) {
+        self.in_flight.insert(encode(&endpoint), encode(&batch));
+    }
+
+    fn remove_in_flight(&mut self, endpoint: &RemoteCallEndpoint) {
+        self.in_flight.remove(&encode(endpoint));
+    }
+
+    fn take_in_flight(&mut self) -> Vec<(RemoteCallEndpoint, EncodedEventBatch)> {
+        let entries: Vec<(Blob, Blob)> = self.in_flight.iter().collect();
+
+        entries
+            .into_iter()
+            .map(|(endpoint, batch)| {
+                self.in_flight.remove(&endpoint);
+
+                (decode(&endpoint), decode(&batch))
+            })
+            .collect()
+    }
+
+    fn get_batch_format(&self, endpoint: &RemoteCallEndpoint) -> BatchFormat {
+        self.batch_formats
+            .get(&encode(endpoint))
+            .map(|it| decode(&it))
+            .unwrap_or_default()
+    }
+
+    fn set_batch_format(&mut self, endpoint: &RemoteCallEndpoint, format: BatchFormat) {
+        self.batch_formats.insert(encode(endpoint), encode(&format));
+    }
+
+    fn next_emitted_event_seq(&mut self) -> u64 {
+        let mut meta = self.get_meta();
+        meta.emitted_events += 1;
+
+        let seq = meta.emitted_events - 1;
+        self.set_meta(meta);
+
+        seq
+    }
+
+    fn set_retained_event(&mut self, event: Event) {
+        self.retained_events
+            .insert(encode(&event.canonical_topics()), encode(&event));
+    }
+
+    fn remove_retained_event(&mut self, canonical_topics: &BTreeSet<EventField>) {
+        self.retained_events.remove(&encode(canonical_topics));
+    }
+
+    fn match_retained_events(&self, filter: &EventFilter) -> Vec<Event> {
+        self.retained_events
+            .iter()
+            .filter(|(topics, _)| filter.0.is_subset(&decode::<BTreeSet<EventField>>(topics)))
+            .map(|(_, event)| decode::<Event>(&event))
+            .collect()
+    }
+
+    fn append_to_event_log(&mut self, event: &Event) -> u64 {
+        let mut meta = self.get_meta();
+        let idx = meta.next_logged_idx;
+        meta.next_logged_idx += 1;
+        self.set_meta(meta);
+
+        // big-endian, so the log is iterated in order
+        let key = Blob::from(idx.to_be_bytes());
+
+        if let Some(compaction_key) = event.get_compaction_key() {
+            self.latest_logged
+                .insert(encode(&compaction_key), key.clone());
+        }
+        self.event_log.insert(key, encode(event));
+
+        idx
+    }
+
+    fn get_logged_event(&self, idx: u64) -> Option<Event> {
+        self.event_log
+            .get(&Blob::from(idx.to_be_bytes()))
+            .map(|it| decode(&it))
+    }
+
+    fn get_logged_events(&self, from: u64, limit: usize) -> Vec<(u64, Event)> {
+        self.event_log
+            .range(Blob::from(from.to_be_bytes())..)
+            .take(limit)
+            .map(|(idx, event)| {
+                (
+                    u64::from_be_bytes(idx[..].try_into().unwrap()),
+                    decode(&event),
+                )
+            })
+            .collect()
+    }
+
+    fn get_event_log_len(&self) -> u64 {
+        self.event_log.len()
+    }
+
+    fn remove_logged_event(&mut self, idx: u64) {
+        self.event_log.remove(&Blob::from(idx.to_be_bytes()));
+    }
+
+    fn get_latest_logged_idx(&self, key: &(Vec<u8>, Vec<u8>)) -> Option<u64> {
+        self.latest_logged
+            .get(&encode(key))
+            .map(|idx| u64::from_be_bytes(idx[..].try_into().unwrap()))
+    }
+
+    fn has_event_schema(&self, name: &str) -> bool {
+        self.event_schemas.contains_key(&encode(&name))
+    }
+
+    fn add_event_schema(&mut self, schema: EventSchema) {
+        self.event_schemas
+            .insert(encode(&schema.name), encode(&schema));
+    }
+
+    fn get_event_schemas(&self) -> Vec<EventSchema> {
+        self.event_schemas
+            .iter()
+            .map(|(_, schema)| decode(&schema))
+            .collect()
+    }
+
+    fn get_epoch(&self) -> u64 {
+        self.get_meta().epoch
+    }
+
+    fn set_epoch(&mut self, epoch: u64) {
+        let mut meta = self.get_meta();
+        meta.epoch = epoch;
+
+        self.set_meta(meta);
+    }
+}
+
+fn encode<T: CandidType>(value: &T) -> Blob {
+    encode_one(value).expect("Unable to encode a stable storage value")
+}
+
+fn decode<T: CandidType + for<'de> Deserialize<'de>>(bytes: &[u8]) -> T {
+    decode_one(bytes).expect("Unable to decode a stable storage value")
+}
+
+/// Returns the current value of the counter stored under `key` and increments it by `step`
+fn next_counter(counters: &mut StableBTreeMap<Blob, Blob, Memory>, key: Blob, step: u64) -> u64 {
+    let value = counters
+        .get(&key)
+        .map(|it| u64::from_be_bytes(it[..].try_into().unwrap()))
+        .unwrap_or_default();
+
+    counters.insert(key, Blob::from((value + step).to_be_bytes()));
+
+    value
+}
+
+fn listener_key(filter: &EventFilter, endpoint: &RemoteCallEndpoint) -> Blob {
+    let mut key = index_prefix(filter);
+    key.extend_from_slice(&prefixed(&encode(filter)));
+    key.extend_from_slice(&encode(endpoint));
+
+    key
+}
+
+/// The prefix of all the keys of listeners indexed by the first topic of `filter`
+fn index_prefix(filter: &EventFilter) -> Blob {
+    match filter.0.iter().next() {
+        Some(topic) => prefixed(&encode(topic)),
+        None => prefixed(&[]),
+    }
+}
+
+/// Length-prefixes `part`, so it could be used to range-scan keys starting with it
+fn prefixed(part: &[u8]) -> Blob {
+    let mut key = Blob::from((part.len() as u32).to_be_bytes());
+    key.extend_from_slice(part);
+
+    key
+}
+
+fn split_prefixed(key: &[u8]) -> (&[u8], &[u8]) {
+    let len = u32::from_be_bytes(key[..4].try_into().unwrap()) as usize;
+
+    (&key[4..4 + len], &key[4 + len..])
+}
+
+fn indexed(prefix: &[u8], idx: u64) -> Blob {
+    let mut key = Blob::from(prefix);
+    key.extend_from_slice(&idx.to_be_bytes());
+
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event_hub::EventHub;
+    use crate::stable_storage::StableStorage;
+    use crate::storage::EventHubStorage;
+    use crate::types::{Event, EventField, EventFilter, RemoteCallEndpoint};
+    use candid::Principal;
+    use ic_stable_structures::memory_manager::MemoryManager;
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    #[test]
+    fn stable_storage_survives_reinit() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut event_hub =
+            EventHub::with_storage(10, 1024, StableStorage::init(&memory_manager, 0));
+        event_hub.set_event_log_enabled(true);
+
+        let field = EventField {
+            name: String::from("1"),
+            value: vec![1],
+        };
+        let endpoint = RemoteCallEndpoint {
+            canister_id: Principal::from_slice(&[1]),
+            method_name: String::from("test"),
+        };
+
+        event_hub.add_event_listener(
+            EventFilter(vec![field.clone()].into_iter().collect()),
+            endpoint.method_name.clone(),
+            endpoint.canister_id,
+        );
+
+        let event = Event {
+            topics: vec![field].into_iter().collect(),
+            values: vec![],
+        };
+        event_hub
+            .push_pending_event(event.clone().into(), 0)
+            .unwrap();
+        event_hub.push_pending_event(event.into(), 5).unwrap();
+
+        // simulating an upgrade - everything is read back from the same memory
+        let mut event_hub =
+            EventHub::with_storage(10, 1024, StableStorage::init(&memory_manager, 0));
+
+        assert_eq!(event_hub.get_event_log_len(), 2);
+
+        event_hub.transform_pending_to_ready_by_time(5);
+        assert!(event_hub.pop_pending_events().is_none());
+
+        event_hub.transform_pending_to_ready_by_time(10);
+        let (ready_endpoint, batches) = event_hub.pop_pending_events().unwrap();
+
+        assert_eq!(ready_endpoint, endpoint);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].events_count, 2);
+        assert!(event_hub.pop_pending_events().is_none());
+    }
+
+    #[test]
+    fn listeners_are_matched_by_topic_index() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut storage = StableStorage::init(&memory_manager, 0);
+
+        let field = |name: &str| EventField {
+            name: String::from(name),
+            value: vec![1],
+        };
+        let endpoint = |method_name: &str| RemoteCallEndpoint {
+            canister_id: Principal::from_slice(&[1]),
+            method_name: String::from(method_name),
+        };
+
+        storage.add_listener(EventFilter::empty(), endpoint("any"));
+        storage.add_listener(
+            EventFilter(vec![field("a")].into_iter().collect()),
+            endpoint("a"),
+        );
+        storage.add_listener(
+            EventFilter(vec![field("a"), field("b")].into_iter().collect()),
+            endpoint("ab"),
+        );
+        storage.add_listener(
+            EventFilter(vec![field("b")].into_iter().collect()),
+            endpoint("b"),
+        );
+
+        let mut matched = storage.match_listeners(&vec![field("b")].into_iter().collect());
+        matched.sort();
+        assert_eq!(matched, vec![endpoint("any"), endpoint("b")]);
+
+        let mut matched =
+            storage.match_listeners(&vec![field("a"), field("b")].into_iter().collect());
+        matched.sort();
+        assert_eq!(
+            matched,
+            vec![
+                endpoint("a"),
+                endpoint("ab"),
+                endpoint("any"),
+                endpoint("b")
+            ]
+        );
+
+        assert!(storage
+            .remove_listener(&EventFilter::empty(), &endpoint("any"))
+            .is_ok());
+        assert_eq!(
+            storage.remove_listener(&EventFilter::empty(), &endpoint("any")),
+            Err(String::from("No such filter"))
+        );
+        assert_eq!(
+            storage.remove_listener(
+                &EventFilter(vec![field("a")].into_iter().collect()),
+                &endpoint("b")
+            ),
+            Err(String::from("No such listener in that filter"))
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::cell::{Cell, RefCell};
+use std::thread::LocalKey;
+
+use crate::event_hub::EventHub;
+use crate::storage::HeapStorage;
+
+/// A lazily initialized container for the `EventHub` of a canister, meant to be kept in a
+/// `thread_local!` static
+///
+/// The hub is only accessible inside of `with()` closures, so a borrow of it never spans an `await`
+pub struct EventHubCell<S = HeapStorage> {
+    hub: RefCell<Option<EventHub<S>>>,
+    init: fn() -> EventHub<S>,
+    scheduled_delivery: Cell<Option<u64>>,
+}
+
+impl<S> EventHubCell<S> {
+    pub fn new(init: fn() -> EventHub<S>) -> Self {
+        Self {
+            hub: RefCell::new(None),
+            init,
+            scheduled_delivery: Cell::new(None),
+        }
+    }
+
+    /// Runs `f` with a mutable reference to the hub, initializing it first if needed
+    ///
+    /// Panics if called again from inside of `f`
+    pub fn with<R>(&self, f: impl FnOnce(&mut EventHub<S>) -> R) -> R {
+        let mut hub = self.hub.borrow_mut();
+
+        f(hub.get_or_insert_with(self.init))
+    }
+
+    pub fn take(&self) -> Option<EventHub<S>> {
+        self.hub.borrow_mut().take()
+    }
+
+    pub fn put(&self, state: Option<EventHub<S>>) {
+        *self.hub.borrow_mut() = state;
+    }
+
+    /// Returns the deadline a delivery timer is currently set for, if any
+    pub fn get_scheduled_delivery(&self) -> Option<u64> {
+        self.scheduled_delivery.get()
+    }
+
+    pub fn set_scheduled_delivery(&self, deadline: u64) {
+        self.scheduled_delivery.set(Some(deadline));
+    }
+
+    /// Forgets the scheduled delivery, if its deadline has already come
+    ///
+    /// A timer set for a later deadline is left as it is, since it will fire anyway
+    pub fn clear_scheduled_delivery(&self, now: u64) {
+        if matches!(self.scheduled_delivery.get(), Some(deadline) if deadline <= now) {
+            self.scheduled_delivery.set(None);
+        }
+    }
+}
+
+/// Gives a short-living mutable access to an `EventHub`
+///
+/// Implemented for the thread-local `EventHubCell` used by macros and for a plain `RefCell`, so the
+/// delivery code could also drive hubs that live outside of canisters
+pub trait EventHubAccess<S> {
+    fn with_hub<R>(&self, f: impl FnOnce(&mut EventHub<S>) -> R) -> R;
+}
+
+impl<S: 'static> EventHubAccess<S> for LocalKey<EventHubCell<S>> {
+    fn with_hub<R>(&self, f: impl FnOnce(&mut EventHub<S>) -> R) -> R {
+        self.with(|cell| cell.with(f))
+    }
+}
+
+impl<S> EventHubAccess<S> for RefCell<EventHub<S>> {
+    fn with_hub<R>(&self, f: impl FnOnce(&mut EventHub<S>) -> R) -> R {
+        f(&mut self.borrow_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event_hub::EventHub;
+    use crate::state::EventHubCell;
+
+    #[test]
+    fn cell_is_initialized_lazily() {
+        let cell = EventHubCell::new(|| EventHub::new(10, 20));
+        assert!(cell.take().is_none());
+
+        let duration = cell.with(|hub| hub.batch_making_duration_nano);
+        assert_eq!(duration, 10);
+
+        cell.put(Some(EventHub::new(30, 40)));
+        let duration = cell.with(|hub| hub.batch_making_duration_nano);
+        assert_eq!(duration, 30);
+
+        assert!(cell.take().is_some());
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{btree_map, BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+
+use candid::{CandidType, Deserialize};
+
+use crate::types::{
+    BatchFormat, EncodedEventBatch, Event, EventField, EventFilter, EventSchema,
+    RemoteCallEndpoint, TimestampedRemoteCallEndpoint,
+};
+
+/// Size, creation time and encoding of a batch which is still being filled with events
+#[derive(Clone, Copy, Debug, CandidType, Deserialize)]
+pub struct PendingBatchMeta {
+    pub timestamp: u64,
+    pub size_bytes: usize,
+    pub compact: bool,
+}
+
+/// A place where `EventHub` keeps its subscriptions, batches and logged events
+///
+/// `HeapStorage` keeps everything on the heap and is serialized together with the hub on upgrade,
+/// `StableStorage` (enabled by the `stable-memory` feature) keeps everything in stable memory
+pub trait EventHubStorage {
+    fn add_listener(&mut self, filter: EventFilter, endpoint: RemoteCallEndpoint);
+    fn remove_listener(
+        &mut self,
+        filter: &EventFilter,
+        endpoint: &RemoteCallEndpoint,
+    ) -> Result<(), String>;
+    fn match_listeners(&self, topics: &BTreeSet<EventField>) -> Vec<RemoteCallEndpoint>;
+
+    fn get_pending_batch_meta(&self, endpoint: &RemoteCallEndpoint) -> Option<PendingBatchMeta>;
+    fn start_pending_batch(&mut self, endpoint: RemoteCallEndpoint, batch: EncodedEventBatch);
+    fn append_to_pending_batch(&mut self, endpoint: &RemoteCallEndpoint, content: &[u8]);
+    fn take_pending_batch(&mut self, endpoint: &RemoteCallEndpoint) -> Option<EncodedEventBatch>;
+
+    fn push_to_queue(&mut self, entry: TimestampedRemoteCallEndpoint);
+    fn peek_queue(&self) -> Option<TimestampedRemoteCallEndpoint>;
+    fn pop_queue(&mut self) -> Option<TimestampedRemoteCallEndpoint>;
+
+    fn push_ready_batch(&mut self, endpoint: RemoteCallEndpoint, batch: EncodedEventBatch);
+    fn pop_ready_batches(&mut self) -> Option<(RemoteCallEndpoint, Vec<EncodedEventBatch>)>;
+    fn has_ready_batches(&self) -> bool;
+    fn get_ready_endpoints(&self) -> BTreeSet<RemoteCallEndpoint>;
+
+    /// Returns the moment before which batches are not sent to `endpoint`, after it rejected one
+    fn get_retry_deadline(&self, endpoint: &RemoteCallEndpoint) -> Option<u64>;
+    fn set_retry_deadline(&mut self, endpoint: &RemoteCallEndpoint, deadline: Option<u64>);
+
+    /// Returns the sequence number for the next batch sent to `endpoint` and increments it
+    fn next_batch_seq(&mut self, endpoint: &RemoteCallEndpoint) -> u64;
+    /// Returns the sequence number of the next event sent to `endpoint` and skips `events_count`
+    /// numbers
+    fn next_event_seq(&mut self, endpoint: &RemoteCallEndpoint, events_count: usize) -> u64;
+    fn is_in_flight(&self, endpoint: &RemoteCallEndpoint) -> bool;
+    /// Marks the batch as sent to `endpoint` and not acknowledged yet, keeping a copy of it
+    fn set_in_flight(&mut self, endpoint: RemoteCallEndpoint, batch: EncodedEventBatch);
+    fn remove_in_flight(&mut self, endpoint: &RemoteCallEndpoint);
+    /// Removes every in-flight mark, returning the batches that were not acknowledged
+    fn take_in_flight(&mut self) -> Vec<(RemoteCallEndpoint, EncodedEventBatch)>;
+
+    fn get_batch_format(&self, endpoint: &RemoteCallEndpoint) -> BatchFormat;
+    fn set_batch_format(&mut self, endpoint: &RemoteCallEndpoint, format: BatchFormat);
+
+    /// Returns the sequence number of the next emitted event and increments it
+    fn next_emitted_event_seq(&mut self) -> u64;
+
+    /// Keeps the event as the last one emitted with its topics, replacing the previous one.
+    /// Retained events are keyed by their canonical topics, see `Event::canonical_topics()`.
+    fn set_retained_event(&mut self, event: Event);
+    fn remove_retained_event(&mut self, canonical_topics: &BTreeSet<EventField>);
+    /// Returns retained events which match the (canonical) filter
+    fn match_retained_events(&self, filter: &EventFilter) -> Vec<Event>;
+
+    /// Appends the event to the log and returns its index. Indices are never reused, so they stay
+    /// valid after older events are removed.
+    fn append_to_event_log(&mut self, event: &Event) -> u64;
+    fn get_logged_event(&self, idx: u64) -> Option<Event>;
+    /// Returns up to `limit` logged events with indices starting from `from`, in the log order
+    fn get_logged_events(&self, from: u64, limit: usize) -> Vec<(u64, Event)>;
+    fn get_event_log_len(&self) -> u64;
+    fn remove_logged_event(&mut self, idx: u64);
+    /// Returns the index of the latest logged event with the compaction key
+    fn get_latest_logged_idx(&self, key: &(Vec<u8>, Vec<u8>)) -> Option<u64>;
+
+    fn has_event_schema(&self, name: &str) -> bool;
+    /// Adds the schema to the event catalog, replacing the one with the same name
+    fn add_event_schema(&mut self, schema: EventSchema);
+    fn get_event_schemas(&self) -> Vec<EventSchema>;
+
+    fn get_epoch(&self) -> u64;
+    fn set_epoch(&mut self, epoch: u64);
+}
+
+/// Default `EventHub` storage that keeps everything on the heap
+#[derive(Default, CandidType, Deserialize)]
+pub struct HeapStorage {
+    pub(crate) listeners: HashMap<EventFilter, HashSet<RemoteCallEndpoint>>,
+    pub(crate) pending_batch: HashMap<RemoteCallEndpoint, EncodedEventBatch>,
+    pub(crate) pending_batch_queue: BinaryHeap<TimestampedRemoteCallEndpoint>,
+    pub(crate) ready_batches: BTreeMap<RemoteCallEndpoint, Vec<EncodedEventBatch>>,
+    pub(crate) retry_deadlines: HashMap<RemoteCallEndpoint, u64>,
+    pub(crate) event_log: BTreeMap<u64, Event>,
+    pub(crate) next_logged_idx: u64,
+    pub(crate) latest_logged: BTreeMap<(Vec<u8>, Vec<u8>), u64>,
+    pub(crate) epoch: u64,
+    pub(crate) batch_seqs: HashMap<RemoteCallEndpoint, u64>,
+    pub(crate) event_seqs: HashMap<RemoteCallEndpoint, u64>,
+    pub(crate) in_flight: HashMap<RemoteCallEndpoint, EncodedEventBatch>,
+    pub(crate) batch_formats: HashMap<RemoteCallEndpoint, BatchFormat>,
+    pub(crate) event_schemas: BTreeMap<String, EventSchema>,
+    pub(crate) emitted_events: u64,
+    pub(crate) retained_events: BTreeMap<BTreeSet<EventField>, Event>,
+}
+
+impl EventHubStorage for HeapStorage {
+    fn add_listener(&mut self, filter: EventFilter, endpoint: RemoteCallEndpoint) {
+        let listeners = self.listeners.entry(filter).or_insert_with(HashSet::new);
+
+        listeners.insert(endpoint);
+    }
+
+    fn remove_listener(
+        &mut self,
+        filter: &EventFilter,
+        endpoint: &RemoteCallEndpoint,
+    ) -> Result<(), String> {
+        let listeners = self
+            .listeners
+            .get_mut(filter)
+            .ok_or_else(|| String::from("No such filter"))?;
+
+        if !listeners.remove(endpoint) {
+            Err(String::from("No such listener in that filter"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn match_listeners(&self, topics: &BTreeSet<EventField>) -> Vec<RemoteCallEndpoint> {
+        self.listeners
+            .iter()
+            .filter(|&entry| entry.0 .0.is_subset(topics))
+            .flat_map(|entry| entry.1.clone())
+            .collect()
+    }
+
+    fn get_pending_batch_meta(&self, endpoint: &RemoteCallEndpoint) -> Option<PendingBatchMeta> {
+        self.pending_batch
+            .get(endpoint)
+            .map(|batch| PendingBatchMeta {
+                timestamp: batch.timestamp,
+                size_bytes: batch.content.len(),
+                compact: batch.compact,
+            })
+    }
+
+    fn start_pending_batch(&mut self, endpoint: RemoteCallEndpoint, batch: EncodedEventBatch) {
+        self.pending_batch.insert(endpoint, batch);
+    }
+
+    fn append_to_pending_batch(&mut self, endpoint: &RemoteCallEndpoint, content: &[u8]) {
+        if let Some(batch) = self.pending_batch.get_mut(endpoint) {
+            batch.add_event(content);
+        }
+    }
+
+    fn take_pending_batch(&mut self, endpoint: &RemoteCallEndpoint) -> Option<EncodedEventBatch> {
+        self.pending_batch.remove(endpoint)
+    }
+
+    fn push_to_queue(&mut self, entry: TimestampedRemoteCallEndpoint) {
+        self.pending_batch_queue.push(entry);
+    }
+
+    fn peek_queue(&self) -> Option<TimestampedRemoteCallEndpoint> {
+        self.pending_batch_queue.peek().cloned()
+    }
+
+    fn pop_queue(&mut self) -> Option<TimestampedRemoteCallEndpoint> {
+        self.pending_batch_queue.pop()
+    }
+
+    fn push_ready_batch(&mut self, endpoint: RemoteCallEndpoint, batch: EncodedEventBatch) {
+        match self.ready_batches.entry(endpoint) {
+            btree_map::Entry::Vacant(e) => {
+                e.insert(vec![batch]);
+            }
+            btree_map::Entry::Occupied(mut e) => {
+                e.get_mut().push(batch);
+            }
+        }
+    }
+
+    fn pop_ready_batches(&mut self) -> Option<(RemoteCallEndpoint, Vec<EncodedEventBatch>)> {
+        let (endpoint, _) = self.ready_batches.iter_mut().next_back()?;
+
+        let endpoint = endpoint.clone();
+        let batches = self.ready_batches.remove(&endpoint).unwrap();
+
+        Some((endpoint, batches))
+    }
+
+    fn has_ready_batches(&self) -> bool {
+        !self.ready_batches.is_empty()
+    }
+
+    fn get_ready_endpoints(&self) -> BTreeSet<RemoteCallEndpoint> {
+        self.ready_batches.keys().cloned().collect()
+    }
+
+    fn get_retry_deadline(&self, endpoint: &RemoteCallEndpoint) -> Option<u64> {
+        self.retry_deadlines.get(endpoint).copied()
+    }
+
+    fn set_retry_deadline(&mut self, endpoint: &RemoteCallEndpoint, deadline: Option<u64>) {
+        match deadline {
+            Some(deadline) => self.retry_deadlines.insert(endpoint.clone(), deadline),
+            None => self.retry_deadlines.remove(endpoint),
+        };
+    }
+
+    fn next_batch_seq(&mut self, endpoint: &RemoteCallEndpoint) -> u64 {
+        let seq = self.batch_seqs.entry(endpoint.clone()).or_insert(0);
+        *seq += 1;
+
+        *seq - 1
+    }
+
+    fn next_event_seq(&mut self, endpoint: &RemoteCallEndpoint, events_count: usize) -> u64 {
+        let seq = self.event_seqs.entry(endpoint.clone()).or_insert(0);
+        *seq += events_count as u64;
+
+        *seq - events_count as u64
+    }
+
+    fn is_in_flight(&self, endpoint: &RemoteCallEndpoint) -> bool {
+        self.in_flight.contains_key(endpoint)
+    }
+
+    fn set_in_flight(&mut self, endpoint: RemoteCallEndpoint, batch: EncodedEventBatch) {
+        self.in_flight.insert(endpoint, batch);
+    }
+
+    fn remove_in_flight(&mut self, endpoint: &RemoteCallEndpoint) {
+        self.in_flight.remove(endpoint);
+    }
+
+    fn take_in_flight(&mut self) -> Vec<(RemoteCallEndpoint, EncodedEventBatch)> {
+        self.in_flight.drain().collect()
+    }
+
+    fn get_batch_format(&self, endpoint: &RemoteCallEndpoint) -> BatchFormat {
+        self.batch_formats
+            .get(endpoint)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn set_batch_format(&mut self, endpoint: &RemoteCallEndpoint, format: BatchFormat) {
+        self.batch_formats.insert(endpoint.clone(), format);
+    }
+
+    fn next_emitted_event_seq(&mut self) -> u64 {
+        self.emitted_events += 1;
+
+        self.emitted_events - 1
+    }
+
+    fn set_retained_event(&mut self, event: Event) {
+        self.retained_events.insert(event.canonical_topics(), event);
+    }
+
+    fn remove_retained_event(&mut self, canonical_topics: &BTreeSet<EventField>) {
+        self.retained_events.remove(canonical_topics);
+    }
+
+    fn match_retained_events(&self, filter: &EventFilter) -> Vec<Event> {
+        self.retained_events
+            .iter()
+            .filter(|(topics, _)| filter.0.is_subset(topics))
+            .map(|(_, event)| event.clone())
+            .collect()
+    }
+
+    fn append_to_event_log(&mut self, event: &Event) -> u64 {
+        let idx = self.next_logged_idx;
+        self.next_logged_idx += 1;
+
+        if let Some(key) = event.get_compaction_key() {
+            self.latest_logged.insert(key, idx);
+        }
+        self.event_log.insert(idx, event.clone());
+
+        idx
+    }
+
+    fn get_logged_event(&self, idx: u64) -> Option<Event> {
+        self.event_log.get(&idx).cloned()
+    }
+
+    fn get_logged_events(&self, from: u64, limit: usize) -> Vec<(u64, Event)> {
+        self.event_log
+            .range(from..)
+            .take(limit)
+            .map(|(idx, event)| (*idx, event.clone()))
+            .collect()
+    }
+
+    fn get_event_log_len(&self) -> u64 {
+        self.event_log.len() as u64
+    }
+
+    fn remove_logged_event(&mut self, idx: u64) {
+        self.event_log.remove(&idx);
+    }
+
+    fn get_latest_logged_idx(&self, key: &(Vec<u8>, Vec<u8>)) -> Option<u64> {
+        self.latest_logged.get(key).cloned()
+    }
+
+    fn has_event_schema(&self, name: &str) -> bool {
+        self.event_schemas.contains_key(name)
+    }
+
+    fn add_event_schema(&mut self, schema: EventSchema) {
+        self.event_schemas.insert(schema.name.clone(), schema);
+    }
+
+    fn get_event_schemas(&self) -> Vec<EventSchema> {
+        self.event_schemas.values().cloned().collect()
+    }
+
+    fn get_epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    fn set_epoch(&mut self, epoch: u64) {
+        self.epoch = epoch;
+    }
+}
+
+impl HeapStorage {
+    pub fn get_listeners(&self) -> &HashMap<EventFilter, HashSet<RemoteCallEndpoint>> {
+        &self.listeners
+    }
+}
@@ -0,0 +1,169 @@
+use std::cell::RefCell;
+use std::collections::{btree_map, BTreeMap, BTreeSet};
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::export::Principal;
+
+use crate::types::CallbackInfo;
+
+/// A struct that remembers which emitters this canister is subscribed to and with which callbacks
+///
+/// It is filled automatically by `IEventHubClient::subscribe()` and `IEventHubClient::unsubscribe()`
+/// and is used by listener callbacks to reject event batches sent by unknown canisters
+#[derive(Default, CandidType, Deserialize)]
+pub struct SubscriptionRegistry {
+    pub(crate) emitters: BTreeMap<Principal, BTreeSet<CallbackInfo>>,
+    pub(crate) epochs: BTreeMap<Principal, u64>,
+    pub(crate) last_verified_at: u64,
+    pub(crate) relays: BTreeMap<Principal, BTreeSet<Principal>>,
+}
+
+impl SubscriptionRegistry {
+    pub fn add_callbacks(&mut self, emitter: Principal, callbacks: Vec<CallbackInfo>) {
+        self.emitters
+            .entry(emitter)
+            .or_insert_with(BTreeSet::new)
+            .extend(callbacks);
+    }
+
+    pub fn remove_callbacks(&mut self, emitter: &Principal, callbacks: &[CallbackInfo]) {
+        if let btree_map::Entry::Occupied(mut e) = self.emitters.entry(*emitter) {
+            for callback in callbacks {
+                e.get_mut().remove(callback);
+            }
+
+            if e.get().is_empty() {
+                e.remove();
+                self.epochs.remove(emitter);
+                self.relays.remove(emitter);
+            }
+        }
+    }
+
+    /// Checks whether there is an active subscription to `emitter` which is served by `method_name`
+    pub fn is_subscribed(&self, emitter: &Principal, method_name: &str) -> bool {
+        self.emitters
+            .get(emitter)
+            .map(|callbacks| {
+                callbacks
+                    .iter()
+                    .any(|callback| callback.method_name == method_name)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Remembers relays of `emitter`, which send its batches to this canister
+    pub fn set_relays(&mut self, emitter: Principal, relays: Vec<Principal>) {
+        if relays.is_empty() {
+            self.relays.remove(&emitter);
+        } else if self.emitters.contains_key(&emitter) {
+            self.relays.insert(emitter, relays.into_iter().collect());
+        }
+    }
+
+    /// Checks whether `sender` is either an emitter subscribed with `method_name` or a relay of
+    /// such an emitter
+    pub fn is_sender(&self, sender: &Principal, method_name: &str) -> bool {
+        self.is_subscribed(sender, method_name)
+            || self.relays.iter().any(|(emitter, relays)| {
+                relays.contains(sender) && self.is_subscribed(emitter, method_name)
+            })
+    }
+
+    pub fn get_callbacks(&self, emitter: &Principal) -> Option<&BTreeSet<CallbackInfo>> {
+        self.emitters.get(emitter)
+    }
+
+    pub fn get_emitters(&self) -> Vec<Principal> {
+        self.emitters.keys().cloned().collect()
+    }
+
+    /// Returns the subscription epoch of `emitter` at the moment of the last successful verification
+    pub fn get_epoch(&self, emitter: &Principal) -> Option<u64> {
+        self.epochs.get(emitter).cloned()
+    }
+
+    pub fn set_epoch(&mut self, emitter: Principal, epoch: u64) {
+        if self.emitters.contains_key(&emitter) {
+            self.epochs.insert(emitter, epoch);
+        }
+    }
+}
+
+thread_local! {
+    static SUBSCRIPTION_REGISTRY: RefCell<SubscriptionRegistry> = RefCell::new(SubscriptionRegistry::default());
+}
+
+pub fn with_subscription_registry<R>(f: impl FnOnce(&mut SubscriptionRegistry) -> R) -> R {
+    SUBSCRIPTION_REGISTRY.with(|registry| f(&mut registry.borrow_mut()))
+}
+
+pub fn _take_subscription_registry_state() -> SubscriptionRegistry {
+    SUBSCRIPTION_REGISTRY.with(|registry| registry.take())
+}
+
+pub fn _put_subscription_registry_state(state: SubscriptionRegistry) {
+    SUBSCRIPTION_REGISTRY.with(|registry| registry.replace(state));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subscription_registry::SubscriptionRegistry;
+    use crate::types::{CallbackInfo, EventFilter};
+    use candid::Principal;
+
+    #[test]
+    fn registry_tracks_callbacks() {
+        let mut registry = SubscriptionRegistry::default();
+
+        let emitter_1 = Principal::from_slice(&[1]);
+        let emitter_2 = Principal::from_slice(&[2]);
+
+        let callback = CallbackInfo {
+            filter: EventFilter::empty(),
+            method_name: String::from("events_callback"),
+            format: None,
+        };
+
+        registry.add_callbacks(emitter_1, vec![callback.clone()]);
+
+        assert!(registry.is_subscribed(&emitter_1, "events_callback"));
+        assert!(!registry.is_subscribed(&emitter_1, "other_callback"));
+        assert!(!registry.is_subscribed(&emitter_2, "events_callback"));
+
+        registry.remove_callbacks(&emitter_1, &[callback]);
+
+        assert!(!registry.is_subscribed(&emitter_1, "events_callback"));
+        assert!(registry.get_emitters().is_empty());
+    }
+
+    #[test]
+    fn relays_are_accepted_as_senders() {
+        let mut registry = SubscriptionRegistry::default();
+
+        let emitter = Principal::from_slice(&[1]);
+        let relay = Principal::from_slice(&[2]);
+        let stranger = Principal::from_slice(&[3]);
+
+        let callback = CallbackInfo {
+            filter: EventFilter::empty(),
+            method_name: String::from("events_callback"),
+            format: None,
+        };
+
+        // relays of emitters this canister is not subscribed to are ignored
+        registry.set_relays(emitter, vec![relay]);
+        assert!(!registry.is_sender(&relay, "events_callback"));
+
+        registry.add_callbacks(emitter, vec![callback.clone()]);
+        registry.set_relays(emitter, vec![relay]);
+
+        assert!(registry.is_sender(&emitter, "events_callback"));
+        assert!(registry.is_sender(&relay, "events_callback"));
+        assert!(!registry.is_sender(&relay, "other_callback"));
+        assert!(!registry.is_sender(&stranger, "events_callback"));
+
+        registry.remove_callbacks(&emitter, &[callback]);
+        assert!(!registry.is_sender(&relay, "events_callback"));
+    }
+}
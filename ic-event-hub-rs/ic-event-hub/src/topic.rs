@@ -0,0 +1,199 @@
+use candid::parser::value::{IDLArgs, IDLField, IDLValue, VariantValue};
+use candid::types::{Label, TypeEnv};
+use candid::{decode_one, encode_one, CandidType, Deserialize, Int};
+
+/// Encodes a topic value canonically, so equal values always produce equal bytes
+///
+/// Plain `encode_one()` depends on the exact candid type of the value - `5u32` and `Nat::from(5)`
+/// are encoded differently and a filter built with one of them would never match an event with the
+/// other. Topics are still sent with plain `encode_one()`, so older emitters and listeners keep
+/// understanding them, but the hub compares them in this form only. The canonical encoding erases
+/// such differences:
+/// * all integer types (`nat8`..`nat`, `int8`..`int`) are encoded as `int`;
+/// * `float32` is encoded as `float64`;
+/// * record and variant fields are referred to by their ids and sorted by them.
+pub fn encode_topic<T: CandidType>(value: &T) -> Vec<u8> {
+    let bytes = encode_one(value).expect("Unable to encode a topic");
+
+    canonicalize_topic(&bytes).expect("Unable to canonicalize a topic")
+}
+
+/// Same as `encode_topic()`, but for dynamically typed values, e.g. the ones parsed from a
+/// candid text
+pub fn encode_topic_value(value: &IDLValue) -> Vec<u8> {
+    IDLArgs::new(&[normalize(value.clone())])
+        .to_bytes()
+        .expect("Unable to encode a topic")
+}
+
+/// Re-encodes an encoded topic value canonically. Returns `None` if the bytes are not a single
+/// candid value.
+pub fn canonicalize_topic(bytes: &[u8]) -> Option<Vec<u8>> {
+    // most topics are primitives which are canonical already, e.g. names and principals
+    if is_canonical_primitive(bytes) {
+        return Some(bytes.to_vec());
+    }
+
+    let mut args = IDLArgs::from_bytes(bytes).ok()?;
+    if args.args.len() != 1 {
+        return None;
+    }
+
+    IDLArgs::new(&[normalize(args.args.remove(0))])
+        .to_bytes()
+        .ok()
+}
+
+/// Decodes a topic value encoded with `encode_topic()` (or with plain `encode_one()`) into its
+/// original type
+pub fn decode_topic<T: CandidType + for<'de> Deserialize<'de>>(bytes: &[u8]) -> Result<T, String> {
+    if let Ok(value) = decode_one(bytes) {
+        return Ok(value);
+    }
+
+    let mut args = IDLArgs::from_bytes(bytes).map_err(|e| e.to_string())?;
+    if args.args.len() != 1 {
+        return Err(String::from("A topic should be a single candid value"));
+    }
+
+    // numbers are converted to the expected types the same way as the ones parsed from text
+    let typed = IDLArgs::new(&[denormalize(args.args.remove(0))])
+        .to_bytes_with_types(&TypeEnv::new(), &[T::ty()])
+        .map_err(|e| e.to_string())?;
+
+    decode_one(&typed).map_err(|e| e.to_string())
+}
+
+/// Checks whether the bytes are a single value of a primitive type which `normalize()` keeps as it
+/// is: an empty type table, a single argument of type `null`, `bool`, `int`, `float64`, `text`,
+/// `reserved`, `empty` or `principal`
+fn is_canonical_primitive(bytes: &[u8]) -> bool {
+    bytes.len() > 6
+        && bytes.starts_with(b"DIDL\x00\x01")
+        && matches!(
+            bytes[6],
+            0x7f | 0x7e | 0x7c | 0x72 | 0x71 | 0x70 | 0x6f | 0x68
+        )
+}
+
+fn normalize(value: IDLValue) -> IDLValue {
+    match value {
+        IDLValue::Nat8(n) => IDLValue::Int(Int::from(n as i64)),
+        IDLValue::Nat16(n) => IDLValue::Int(Int::from(n as i64)),
+        IDLValue::Nat32(n) => IDLValue::Int(Int::from(n as i64)),
+        IDLValue::Nat64(n) => IDLValue::Int(Int(n.into())),
+        IDLValue::Nat(n) => IDLValue::Int(Int(n.0.into())),
+        IDLValue::Int8(n) => IDLValue::Int(Int::from(n as i64)),
+        IDLValue::Int16(n) => IDLValue::Int(Int::from(n as i64)),
+        IDLValue::Int32(n) => IDLValue::Int(Int::from(n as i64)),
+        IDLValue::Int64(n) => IDLValue::Int(Int::from(n)),
+        IDLValue::Number(n) => match n.parse::<Int>() {
+            Ok(n) => IDLValue::Int(n),
+            Err(_) => IDLValue::Number(n),
+        },
+        IDLValue::Float32(n) => IDLValue::Float64(n as f64),
+        IDLValue::Opt(value) => IDLValue::Opt(Box::new(normalize(*value))),
+        IDLValue::Vec(values) => IDLValue::Vec(values.into_iter().map(normalize).collect()),
+        IDLValue::Record(fields) => {
+            let mut fields: Vec<_> = fields.into_iter().map(normalize_field).collect();
+            fields.sort_by_key(|field| field.id.get_id());
+
+            IDLValue::Record(fields)
+        }
+        IDLValue::Variant(VariantValue(field, _)) => {
+            // the only field of the inferred variant type
+            IDLValue::Variant(VariantValue(Box::new(normalize_field(*field)), 0))
+        }
+        value => value,
+    }
+}
+
+fn normalize_field(field: IDLField) -> IDLField {
+    IDLField {
+        id: Label::Id(field.id.get_id()),
+        val: normalize(field.val),
+    }
+}
+
+fn denormalize(value: IDLValue) -> IDLValue {
+    match value {
+        IDLValue::Int(n) => IDLValue::Number(n.to_string()),
+        IDLValue::Opt(value) => IDLValue::Opt(Box::new(denormalize(*value))),
+        IDLValue::Vec(values) => IDLValue::Vec(values.into_iter().map(denormalize).collect()),
+        IDLValue::Record(fields) => IDLValue::Record(
+            fields
+                .into_iter()
+                .map(|field| IDLField {
+                    id: field.id,
+                    val: denormalize(field.val),
+                })
+                .collect(),
+        ),
+        IDLValue::Variant(VariantValue(field, idx)) => IDLValue::Variant(VariantValue(
+            Box::new(IDLField {
+                id: field.id,
+                val: denormalize(field.val),
+            }),
+            idx,
+        )),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::topic::{canonicalize_topic, decode_topic, encode_topic, encode_topic_value};
+    use candid::parser::value::IDLValue;
+    use candid::{encode_one, CandidType, Deserialize, Nat, Principal};
+
+    #[derive(CandidType, Deserialize, Debug, PartialEq)]
+    struct Pair {
+        b: u8,
+        a: String,
+    }
+
+    #[test]
+    fn equal_values_are_encoded_equally() {
+        assert_eq!(encode_topic(&5u32), encode_topic(&Nat::from(5u64)));
+        assert_eq!(encode_topic(&5u8), encode_topic(&5i64));
+        assert_eq!(
+            encode_topic(&Some(5u16)),
+            encode_topic_value(&IDLValue::Opt(Box::new(IDLValue::Nat(Nat::from(5u64)))))
+        );
+        assert_ne!(encode_topic(&5u32), encode_topic(&6u32));
+
+        // strings are encoded the same way as before
+        assert_eq!(encode_topic(&"kek"), encode_one("kek").unwrap());
+
+        let encoded = encode_one(7u64).unwrap();
+        assert_eq!(canonicalize_topic(&encoded), Some(encode_topic(&7u32)));
+        assert_eq!(canonicalize_topic(&[1, 2, 3]), None);
+
+        // primitives which are canonical already are returned as they are
+        let encoded = encode_one(Principal::from_slice(&[1])).unwrap();
+        assert_eq!(canonicalize_topic(&encoded), Some(encoded));
+        assert_eq!(
+            canonicalize_topic(&encode_one(true).unwrap()),
+            Some(encode_one(true).unwrap())
+        );
+    }
+
+    #[test]
+    fn topics_are_decoded_into_original_types() {
+        assert_eq!(decode_topic::<u32>(&encode_topic(&5u32)), Ok(5));
+        assert_eq!(decode_topic::<i8>(&encode_topic(&-5i8)), Ok(-5));
+        assert_eq!(
+            decode_topic::<Option<u64>>(&encode_topic(&Some(5u64))),
+            Ok(Some(5))
+        );
+
+        let pair = Pair {
+            b: 1,
+            a: String::from("kek"),
+        };
+        assert_eq!(decode_topic::<Pair>(&encode_topic(&pair)), Ok(pair));
+
+        // legacy topics are still decoded
+        assert_eq!(decode_topic::<u64>(&encode_one(5u64).unwrap()), Ok(5));
+    }
+}
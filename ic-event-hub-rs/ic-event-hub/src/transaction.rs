@@ -0,0 +1,55 @@
+use crate::event_hub::EmittedEvent;
+use crate::types::IEvent;
+
+/// Events emitted during an async update, which are passed to the event-hub all at once
+///
+/// `emit()` pushes an event to the event-hub right away, so if an update awaits after it, the
+/// event could be delivered even if the rest of the update fails. Events emitted with a
+/// transaction stay inside it until `commit_events()` is called and are discarded on `rollback()`
+/// (or when the transaction is simply dropped).
+///
+/// Usage:
+/// ```ignore
+/// let mut tx = EventTransaction::new();
+///
+/// tx.emit(WithdrawStartedEvent { amount });
+/// let res = ledger.transfer(amount).await;
+///
+/// if res.is_err() {
+///     tx.rollback();
+///     return;
+/// }
+///
+/// tx.emit(WithdrawFinishedEvent { amount });
+/// let receipts = commit_events(tx).unwrap();
+/// ```
+#[must_use = "events are discarded unless the transaction is committed"]
+#[derive(Default)]
+pub struct EventTransaction {
+    events: Vec<EmittedEvent>,
+}
+
+impl EventTransaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn emit(&mut self, event: impl IEvent) {
+        self.events.push(EmittedEvent::new(&event));
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Discards all the events of this transaction
+    pub fn rollback(self) {}
+
+    pub(crate) fn into_events(self) -> Vec<EmittedEvent> {
+        self.events
+    }
+}
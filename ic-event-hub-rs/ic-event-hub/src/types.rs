@@ -1,11 +1,17 @@
 use std::cmp::{max, min, Ordering};
 use std::collections::{BTreeSet, BinaryHeap};
 
+use candid::parser::value::IDLValue;
 use candid::types::{Serializer, Type};
-use candid::{decode_one, CandidType, Deserialize};
+use candid::{decode_one, encode_one, CandidType, Deserialize};
+use ic_cdk::api::call::RejectionCode;
 use ic_cdk::export::Principal;
 
-use crate::EVENT_NAME_FIELD;
+use crate::topic::{canonicalize_topic, encode_topic_value};
+use crate::{
+    EVENT_EMITTER_FIELD, EVENT_KEY_FIELD, EVENT_NAME_FIELD, EVENT_RETAINED_FIELD,
+    EVENT_TOMBSTONE_FIELD, MAX_UNCOMPRESSED_BATCH_SIZE,
+};
 
 /// Serialized representation of some field of an event
 #[derive(Eq, PartialEq, PartialOrd, Ord, Hash, Clone, Debug, CandidType, Deserialize)]
@@ -22,6 +28,12 @@ pub struct Event {
 }
 
 impl Event {
+    /// Returns the name of the event struct, or `None` if the event has no (valid) name topic
+    pub fn find_name(&self) -> Option<String> {
+        self.find_topic(EVENT_NAME_FIELD)
+            .and_then(|value| decode_one(&value).ok())
+    }
+
     /// Finds a serialized name of the event struct, deserializes it and returns
     pub fn get_name(&self) -> String {
         let encoded_name = self
@@ -34,6 +46,99 @@ impl Event {
 
         decode_one::<String>(encoded_name.as_slice()).unwrap()
     }
+
+    /// Returns the topics with their values re-encoded canonically, the way the hub compares them
+    /// with filters, see `EventFilter::canonicalize()`. The retention mark is not a part of them.
+    pub fn canonical_topics(&self) -> BTreeSet<EventField> {
+        let topics = self
+            .topics
+            .iter()
+            .filter(|field| field.name != EVENT_RETAINED_FIELD)
+            .cloned()
+            .collect();
+
+        EventFilter(topics).canonicalize().0
+    }
+
+    /// Returns the original emitter of the event, if it was relayed by a broker. A malformed
+    /// emitter topic is treated as a missing one.
+    pub fn get_emitter(&self) -> Option<Principal> {
+        self.find_topic(EVENT_EMITTER_FIELD)
+            .and_then(|value| decode_one(&value).ok())
+    }
+
+    /// Marks the event as emitted by `emitter`, replacing the previous mark if there was one
+    pub fn set_emitter(&mut self, emitter: Principal) {
+        self.topics
+            .retain(|field| field.name != EVENT_EMITTER_FIELD);
+        self.topics.insert(emitter_field(emitter));
+    }
+
+    /// Marks the event as a retained one, so a broker which relays it retains it as well
+    pub(crate) fn mark_retained(&mut self) {
+        if !self.is_retained() {
+            self.topics.insert(EventField {
+                name: String::from(EVENT_RETAINED_FIELD),
+                value: encode_one(true).unwrap(),
+            });
+        }
+    }
+
+    /// Returns the key by which the event log is compacted - the encoded event name and the
+    /// canonically encoded value of its `#[topic(key)]` field. Events without a key are never
+    /// compacted.
+    pub fn get_compaction_key(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let key = self.find_topic(EVENT_KEY_FIELD)?;
+        let key = canonicalize_topic(&key).unwrap_or(key);
+        let name = self.find_topic(EVENT_NAME_FIELD).unwrap_or_default();
+
+        Some((name, key))
+    }
+
+    /// Turns the event into a tombstone, which marks the entity with the same compaction key as
+    /// deleted. Values are dropped, so a listener should check `is_tombstone()` before passing the
+    /// event to `IEvent::from_event()`.
+    pub fn into_tombstone(mut self) -> Event {
+        self.values.clear();
+        self.topics.insert(EventField {
+            name: String::from(EVENT_TOMBSTONE_FIELD),
+            value: encode_one(true).unwrap(),
+        });
+
+        self
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.find_topic(EVENT_TOMBSTONE_FIELD).is_some()
+    }
+
+    fn find_topic(&self, name: &str) -> Option<Vec<u8>> {
+        self.topics
+            .iter()
+            .find(|&field| field.name == name)
+            .map(|field| field.value.clone())
+    }
+}
+
+impl IEvent for Event {
+    fn to_event(&self) -> Event {
+        self.clone()
+    }
+
+    fn from_event(event: Event) -> Self {
+        event
+    }
+
+    fn is_retained(&self) -> bool {
+        self.find_topic(EVENT_RETAINED_FIELD).is_some()
+    }
+}
+
+fn emitter_field(emitter: Principal) -> EventField {
+    EventField {
+        name: String::from(EVENT_EMITTER_FIELD),
+        value: encode_one(emitter).unwrap(),
+    }
 }
 
 /// Represents an struct that could be serialized into an `Event`
@@ -42,6 +147,80 @@ impl Event {
 pub trait IEvent {
     fn to_event(&self) -> Event;
     fn from_event(event: Event) -> Self;
+
+    /// Whether the hub should keep the latest event per distinct set of topics and send it to
+    /// every new listener with a matching filter, set with `#[event(retain)]`
+    fn is_retained(&self) -> bool {
+        false
+    }
+
+    /// Returns the function which describes the event type. The hub adds the type to its event
+    /// catalog the first time such an event is emitted, see `EventHub::get_event_catalog()`.
+    /// `#[derive(Event)]` returns `IEventSchema::event_schema`.
+    fn event_schema_fn(&self) -> Option<fn() -> EventSchema> {
+        None
+    }
+
+    /// Encodes the event for callbacks subscribed with `BatchFormat::Compact`. `#[derive(Event)]`
+    /// encodes the values of all the fields, without their names, as a single candid message.
+    /// Events which have no compact form are sent as they are.
+    fn to_compact_event(&self) -> CompactEvent {
+        CompactEvent::Full(self.to_event())
+    }
+
+    /// Decodes an event received by a `BatchFormat::Compact` callback, returns `None` if the event
+    /// is of some other type
+    fn from_compact_event(event: CompactEvent) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        match event {
+            CompactEvent::Full(event) => Some(Self::from_event(event)),
+            CompactEvent::Fields { .. } => None,
+        }
+    }
+}
+
+/// Name and candid type of a field of some event type
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct EventFieldSchema {
+    pub name: String,
+    pub candid_type: String,
+}
+
+/// Machine-readable description of an event type, so clients written in other languages could
+/// build filters and decode events of this type
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct EventSchema {
+    pub name: String,
+    /// Set with `#[event(version = N)]`, `1` by default
+    pub version: u32,
+    pub topics: Vec<EventFieldSchema>,
+    pub values: Vec<EventFieldSchema>,
+}
+
+impl EventSchema {
+    /// Returns a candid record type declaration with all the fields of this event
+    pub fn to_did(&self) -> String {
+        let fields: Vec<String> = self
+            .topics
+            .iter()
+            .chain(self.values.iter())
+            .map(|field| format!("{} : {}", field.name, field.candid_type))
+            .collect();
+
+        format!(
+            "type {}_v{} = record {{ {} }};",
+            self.name,
+            self.version,
+            fields.join("; ")
+        )
+    }
+}
+
+/// Implemented automatically by `#[derive(Event)]`
+pub trait IEventSchema {
+    fn event_schema() -> EventSchema;
 }
 
 /// A set of topics of interest of a particular event listener
@@ -52,6 +231,50 @@ impl EventFilter {
     pub fn empty() -> Self {
         Self(BTreeSet::new())
     }
+
+    /// Narrows the filter down to events relayed by a broker on behalf of `emitter`
+    pub fn from_emitter(mut self, emitter: Principal) -> Self {
+        self.0.insert(emitter_field(emitter));
+
+        self
+    }
+
+    /// A filter matching all the events of the type named `event_name`, which could be narrowed
+    /// down with `with_topic()` - the way to build filters without the derived `*Filter` struct
+    pub fn for_event(event_name: &str) -> Self {
+        let mut filter = Self::empty();
+        filter.0.insert(EventField {
+            name: String::from(EVENT_NAME_FIELD),
+            value: encode_one(event_name).unwrap(),
+        });
+
+        filter
+    }
+
+    /// Narrows the filter down to events with the topic equal to `value`
+    pub fn with_topic(mut self, name: &str, value: &IDLValue) -> Self {
+        self.0.insert(EventField {
+            name: String::from(name),
+            value: encode_topic_value(value),
+        });
+
+        self
+    }
+
+    /// Re-encodes topic values of the filter canonically, so it matches events with equal topics
+    /// even if it was built with other candid types or in another language. Values which are not
+    /// valid candid are left as they are.
+    pub fn canonicalize(self) -> Self {
+        Self(
+            self.0
+                .into_iter()
+                .map(|field| EventField {
+                    value: canonicalize_topic(&field.value).unwrap_or(field.value),
+                    name: field.name,
+                })
+                .collect(),
+        )
+    }
 }
 
 /// Represents a struct that could be serialized into an `EventFilter`
@@ -62,10 +285,96 @@ pub trait IEventFilter {
     fn from_event_filter(filter: EventFilter) -> Self;
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(Eq, PartialEq, PartialOrd, Ord, Hash, Clone, Debug, CandidType, Deserialize)]
 pub struct CallbackInfo {
     pub filter: EventFilter,
     pub method_name: String,
+    /// `None` means `BatchFormat::Events`
+    pub format: Option<BatchFormat>,
+}
+
+/// The way event batches are passed to a callback method
+#[derive(Eq, PartialEq, PartialOrd, Ord, Hash, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum BatchFormat {
+    /// The method receives a bare `Vec<Event>`
+    Events,
+    /// The method receives an `EventBatch` with the metadata of the batch
+    Envelope,
+    /// The method receives a `CompressedEventBatch`, which is much smaller for batches of many
+    /// similar events
+    Compressed,
+    /// The method receives a bare `Vec<CompactEvent>`, which carries no field names
+    Compact,
+}
+
+impl Default for BatchFormat {
+    fn default() -> Self {
+        BatchFormat::Events
+    }
+}
+
+/// An event batch together with its metadata, as it is received by callbacks subscribed with
+/// `BatchFormat::Envelope`
+///
+/// Sequence numbers are counted separately for each callback, so a gap between them means that
+/// some batch was lost
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EventBatch {
+    pub emitter: Principal,
+    pub batch_seq: u64,
+    pub first_event_seq: u64,
+    pub created_at: u64,
+    pub events: Vec<Event>,
+}
+
+/// An event batch compressed with LZ4, as it is received by callbacks subscribed with
+/// `BatchFormat::Compressed`
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CompressedEventBatch {
+    pub batch_seq: u64,
+    pub events_count: u64,
+    /// Size of the candid message `data` is decompressed into
+    pub uncompressed_size: u64,
+    /// LZ4 block of the candid message of a single `Vec<Event>` argument
+    pub data: Vec<u8>,
+}
+
+impl CompressedEventBatch {
+    pub fn compress(batch_seq: u64, events_count: u64, message: &[u8]) -> Self {
+        Self {
+            batch_seq,
+            events_count,
+            uncompressed_size: message.len() as u64,
+            data: lz4_flex::block::compress(message),
+        }
+    }
+
+    /// Fails if the batch claims to be bigger than `MAX_UNCOMPRESSED_BATCH_SIZE`
+    pub fn decompress(&self) -> Result<Vec<Event>, String> {
+        if self.uncompressed_size > MAX_UNCOMPRESSED_BATCH_SIZE {
+            return Err(format!(
+                "Unable to decompress an event batch - {} bytes is more than the limit of {}",
+                self.uncompressed_size, MAX_UNCOMPRESSED_BATCH_SIZE
+            ));
+        }
+
+        let message = lz4_flex::block::decompress(&self.data, self.uncompressed_size as usize)
+            .map_err(|e| format!("Unable to decompress an event batch - {}", e))?;
+
+        decode_one(&message).map_err(|e| format!("Unable to decode an event batch - {}", e))
+    }
+}
+
+/// An event as it is received by callbacks subscribed with `BatchFormat::Compact`
+///
+/// Use `IEvent::from_compact_event()` of the expected event type to decode it
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum CompactEvent {
+    /// Values of all the fields of the event struct in the order of their declaration, encoded as
+    /// a single candid message
+    Fields { name: String, values: Vec<u8> },
+    /// An event which has no compact form, e.g. the one relayed by a broker
+    Full(Event),
 }
 
 #[derive(CandidType, Deserialize)]
@@ -76,15 +385,34 @@ pub struct CallbackInfoExt {
 
 #[derive(Debug)]
 pub enum EventHubError {
+    #[deprecated(
+        note = "emit() returns an EmitReceipt with matched_endpoints = 0 when nobody listens to the event"
+    )]
     EventHasNoActiveListeners,
     EventIsTooBig,
 }
 
-#[derive(CandidType, Deserialize)]
+/// Outcome of a single emitted event
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct EmitReceipt {
+    /// Sequence number of the event among all the events emitted by this hub
+    pub event_seq: u64,
+    /// How many listeners the event is going to be delivered to - `0` means nobody listens to it
+    pub matched_endpoints: u64,
+    /// Size of the encoded event
+    pub bytes: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
 pub struct EncodedEventBatch {
     pub content: Vec<u8>,
     pub events_count: usize,
     pub timestamp: u64,
+    pub delivery_attempts: u32,
+    pub seq: u64,
+    pub first_event_seq: u64,
+    /// Whether `content` consists of `CompactEvent`s instead of `Event`s
+    pub compact: bool,
 }
 
 impl EncodedEventBatch {
@@ -93,6 +421,10 @@ impl EncodedEventBatch {
             content: Vec::from(content),
             events_count: 1,
             timestamp,
+            delivery_attempts: 0,
+            seq: 0,
+            first_event_seq: 0,
+            compact: false,
         }
     }
 
@@ -102,6 +434,24 @@ impl EncodedEventBatch {
     }
 }
 
+/// A batch that the listener rejected or was unable to receive
+#[derive(Debug)]
+pub struct FailedDelivery {
+    pub endpoint: RemoteCallEndpoint,
+    pub events_count: usize,
+    pub rejection_code: RejectionCode,
+    pub message: String,
+    pub will_retry: bool,
+}
+
+/// Outcome of a single `send_events_async()` call
+#[derive(Default, Debug)]
+pub struct SendReport {
+    pub delivered_batches: u64,
+    pub delivered_events: u64,
+    pub failed: Vec<FailedDelivery>,
+}
+
 #[derive(Eq, CandidType, Deserialize, Clone)]
 pub struct TimestampedRemoteCallEndpoint {
     pub timestamp: u64,
@@ -173,13 +523,32 @@ impl Ord for TimestampedRemoteCallEndpoint {
 
 // ---------- API TYPES ---------------
 
-#[derive(CandidType, Deserialize)]
+#[derive(Clone, CandidType, Deserialize)]
 pub struct SubscribeRequest {
     pub callbacks: Vec<CallbackInfo>,
 }
 
 pub type UnsubscribeRequest = SubscribeRequest;
 
+/// Relays of the emitter, if the listener is served by one of them
+///
+/// Batches of such a listener are sent by its relay, so the listener should accept them from any
+/// of these canisters. Older emitters reply with nothing, which means there are no relays.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct SubscribeResponse {
+    pub relays: Vec<Principal>,
+}
+
+/// A subscription made by a root emitter on behalf of one of its listeners, which is served by
+/// the relay receiving this request
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RelaySubscribeRequest {
+    pub listener: Principal,
+    pub callbacks: Vec<CallbackInfo>,
+}
+
+pub type RelayUnsubscribeRequest = RelaySubscribeRequest;
+
 #[derive(CandidType, Deserialize)]
 pub struct GetSubscribersRequest {
     pub filters: Vec<EventFilter>,
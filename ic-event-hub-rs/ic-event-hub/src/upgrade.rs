@@ -0,0 +1,304 @@
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+
+use candid::{decode_one, encode_one, CandidType, Deserialize};
+use ic_cdk::trap;
+
+use crate::event_hub::EventHub;
+use crate::types::{
+    EncodedEventBatch, EventFilter, RemoteCallEndpoint, TimestampedRemoteCallEndpoint,
+};
+
+/// Version of the serialized `EventHub` layout
+///
+/// It is bumped each time the layout changes, so a state saved by an older version of this crate
+/// could be passed to a migration hook instead of failing to deserialize
+pub const EVENT_HUB_STATE_VERSION: u32 = 2;
+
+/// A function that turns an `EventHub` state of some older layout version into the current one
+pub type EventHubMigration = fn(version: u32, state: Vec<u8>) -> Option<EventHub>;
+
+/// `EventHub` state tagged with the version of its layout, which is saved to stable memory
+/// between upgrades
+#[derive(CandidType, Deserialize)]
+pub struct VersionedEventHubState {
+    pub version: u32,
+    pub state: Vec<u8>,
+}
+
+impl VersionedEventHubState {
+    pub fn new(hub: Option<EventHub>) -> Self {
+        Self {
+            version: EVENT_HUB_STATE_VERSION,
+            state: encode_one(hub).expect("Unable to encode event hub state"),
+        }
+    }
+
+    /// Decodes the saved state, passing it through `migrate` if it was saved with another layout
+    /// version, which is not migrated by this crate itself
+    ///
+    /// Batches which were in flight when the state was saved are put back to the ready ones, since
+    /// calls made before an upgrade never complete
+    pub fn restore(self, migrate: EventHubMigration) -> Option<EventHub> {
+        let hub = match self.version {
+            EVENT_HUB_STATE_VERSION => {
+                decode_one(&self.state).expect("Unable to decode event hub state")
+            }
+            // the same layout, but filters were stored the way they were subscribed with
+            1 => {
+                let hub: Option<EventHub> =
+                    decode_one(&self.state).expect("Unable to decode event hub state");
+
+                hub.map(|mut hub| {
+                    hub.canonicalize_filters();
+                    hub
+                })
+            }
+            version => migrate(version, self.state),
+        };
+
+        hub.map(|mut hub| {
+            hub.reset_in_flight();
+            hub
+        })
+    }
+}
+
+/// `EventHub` layout of the crate versions before `VersionedEventHubState` was introduced, which
+/// was saved to stable memory by canisters themselves, usually as `(canister_state, Option<EventHub>)`
+///
+/// The upgrade mode of `implement_event_emitter!()` falls back to this layout automatically, when
+/// the saved state is not versioned
+#[derive(CandidType, Deserialize)]
+pub struct LegacyEventHub {
+    pub batch_making_duration_nano: u64,
+    pub batch_max_size_bytes: usize,
+    pub listeners: HashMap<EventFilter, HashSet<RemoteCallEndpoint>>,
+    pub pending_batch: HashMap<RemoteCallEndpoint, LegacyEncodedEventBatch>,
+    pub pending_batch_queue: BinaryHeap<TimestampedRemoteCallEndpoint>,
+    pub ready_batches: BTreeMap<RemoteCallEndpoint, Vec<LegacyEncodedEventBatch>>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct LegacyEncodedEventBatch {
+    pub content: Vec<u8>,
+    pub events_count: usize,
+    pub timestamp: u64,
+}
+
+impl From<LegacyEncodedEventBatch> for EncodedEventBatch {
+    fn from(legacy: LegacyEncodedEventBatch) -> Self {
+        let mut batch = EncodedEventBatch::new(&legacy.content, legacy.timestamp);
+        batch.events_count = legacy.events_count;
+
+        batch
+    }
+}
+
+impl From<LegacyEventHub> for EventHub {
+    fn from(legacy: LegacyEventHub) -> Self {
+        let mut hub = EventHub::new(
+            legacy.batch_making_duration_nano,
+            legacy.batch_max_size_bytes,
+        );
+
+        hub.storage.listeners = legacy.listeners;
+        hub.storage.pending_batch = legacy
+            .pending_batch
+            .into_iter()
+            .map(|(endpoint, batch)| (endpoint, batch.into()))
+            .collect();
+        hub.storage.pending_batch_queue = legacy.pending_batch_queue;
+        hub.storage.ready_batches = legacy
+            .ready_batches
+            .into_iter()
+            .map(|(endpoint, batches)| (endpoint, batches.into_iter().map(Into::into).collect()))
+            .collect();
+        hub.canonicalize_filters();
+
+        hub
+    }
+}
+
+/// Default migration hook, which refuses to restore a state of unknown layout version
+pub fn no_migration(version: u32, _state: Vec<u8>) -> Option<EventHub> {
+    trap(
+        format!(
+            "Unable to restore event hub state of version {} (current version is {}) - no migration provided",
+            version, EVENT_HUB_STATE_VERSION
+        )
+        .as_str(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event_hub::EventHub;
+    use crate::storage::EventHubStorage;
+    use crate::types::{
+        EncodedEventBatch, EventField, EventFilter, RemoteCallEndpoint,
+        TimestampedRemoteCallEndpoint,
+    };
+    use crate::upgrade::{
+        LegacyEncodedEventBatch, LegacyEventHub, VersionedEventHubState, EVENT_HUB_STATE_VERSION,
+    };
+    use candid::{decode_args, encode_args, encode_one, Principal, Reserved};
+    use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+
+    #[test]
+    fn versioned_state_works_fine() {
+        let state = VersionedEventHubState::new(Some(EventHub::new(10, 20)));
+        assert_eq!(state.version, EVENT_HUB_STATE_VERSION);
+
+        let hub = state
+            .restore(|_, _| panic!("Migration should not be called"))
+            .unwrap();
+        assert_eq!(hub.batch_making_duration_nano, 10);
+        assert_eq!(hub.batch_max_size_bytes, 20);
+
+        let mut state = VersionedEventHubState::new(None);
+        state.version = EVENT_HUB_STATE_VERSION + 1;
+
+        let hub = state
+            .restore(|version, _| {
+                assert_eq!(version, EVENT_HUB_STATE_VERSION + 1);
+                Some(EventHub::new(30, 40))
+            })
+            .unwrap();
+        assert_eq!(hub.batch_making_duration_nano, 30);
+    }
+
+    #[test]
+    fn legacy_state_is_migrated() {
+        let endpoint = RemoteCallEndpoint {
+            canister_id: Principal::from_slice(&[1]),
+            method_name: String::from("events_callback"),
+        };
+
+        let mut listeners = HashMap::new();
+        listeners.insert(
+            EventFilter::empty(),
+            vec![endpoint.clone()].into_iter().collect::<HashSet<_>>(),
+        );
+
+        let mut pending_batch = HashMap::new();
+        pending_batch.insert(
+            endpoint.clone(),
+            LegacyEncodedEventBatch {
+                content: vec![1, 2, 3],
+                events_count: 3,
+                timestamp: 5,
+            },
+        );
+
+        let mut pending_batch_queue = BinaryHeap::new();
+        pending_batch_queue.push(TimestampedRemoteCallEndpoint {
+            timestamp: 5,
+            endpoint: endpoint.clone(),
+        });
+
+        let legacy = LegacyEventHub {
+            batch_making_duration_nano: 10,
+            batch_max_size_bytes: 20,
+            listeners,
+            pending_batch,
+            pending_batch_queue,
+            ready_batches: BTreeMap::new(),
+        };
+
+        // the way canisters saved it to stable memory with `stable_save()`
+        let saved = encode_args((0u64, Some(legacy))).unwrap();
+        assert!(
+            decode_args::<(Reserved, VersionedEventHubState)>(&saved).is_err(),
+            "Legacy state should not be mistaken for a versioned one"
+        );
+
+        let versioned = encode_args((0u64, VersionedEventHubState::new(None))).unwrap();
+        assert!(decode_args::<(Reserved, VersionedEventHubState)>(&versioned).is_ok());
+
+        let (_, legacy): (u64, Option<LegacyEventHub>) = decode_args(&saved).unwrap();
+        let mut hub = EventHub::from(legacy.unwrap());
+
+        assert_eq!(hub.batch_making_duration_nano, 10);
+        assert_eq!(hub.get_listeners().len(), 1);
+
+        hub.transform_pending_to_ready_by_time(15);
+        let (ready_endpoint, batches) = hub.pop_pending_events().unwrap();
+
+        assert_eq!(ready_endpoint, endpoint);
+        assert_eq!(batches[0].content, vec![1, 2, 3]);
+        assert_eq!(batches[0].events_count, 3);
+    }
+
+    #[test]
+    fn filters_of_version_1_are_canonicalized() {
+        let endpoint = RemoteCallEndpoint {
+            canister_id: Principal::from_slice(&[1]),
+            method_name: String::from("events_callback"),
+        };
+        let filter = |value| {
+            EventFilter(
+                vec![EventField {
+                    name: String::from("amount"),
+                    value,
+                }]
+                .into_iter()
+                .collect(),
+            )
+        };
+
+        // stored the way they were subscribed with, bypassing `add_event_listener()`
+        let mut hub = EventHub::new(10, 20);
+        for value in [encode_one(5u32).unwrap(), encode_one(5u64).unwrap()] {
+            hub.storage
+                .listeners
+                .insert(filter(value), vec![endpoint.clone()].into_iter().collect());
+        }
+
+        let mut state = VersionedEventHubState::new(Some(hub));
+        state.version = 1;
+
+        let hub = state
+            .restore(|_, _| panic!("Migration should not be called"))
+            .unwrap();
+        assert_eq!(hub.get_listeners().len(), 1);
+        assert_eq!(
+            hub.match_event_listeners(&filter(encode_one(5u8).unwrap())),
+            vec![endpoint]
+        );
+    }
+
+    #[test]
+    fn batches_in_flight_are_sent_again_after_upgrade() {
+        let endpoint = RemoteCallEndpoint {
+            canister_id: Principal::from_slice(&[1]),
+            method_name: String::from("events_callback"),
+        };
+
+        let mut hub = EventHub::new(10, 20);
+        hub.set_ordered_delivery(true);
+
+        for seq in 0..2 {
+            let mut batch = EncodedEventBatch::new(&[seq as u8], 0);
+            batch.seq = seq;
+            hub.storage.push_ready_batch(endpoint.clone(), batch);
+        }
+
+        // the call with the first batch is still in flight, when the canister is upgraded
+        let sent = hub.take_batches_to_send(0);
+        assert_eq!(sent[0].1[0].seq, 0);
+        assert!(hub.storage.is_in_flight(&endpoint));
+
+        let mut hub = VersionedEventHubState::new(Some(hub))
+            .restore(|_, _| panic!("Migration should not be called"))
+            .unwrap();
+        assert!(!hub.storage.is_in_flight(&endpoint));
+        assert_eq!(hub.next_batch_deadline(), Some(0));
+
+        let sent = hub.take_batches_to_send(0);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, endpoint);
+        assert_eq!(sent[0].1.len(), 1);
+        assert_eq!(sent[0].1[0].seq, 0);
+        assert_eq!(sent[0].1[0].content, vec![0]);
+    }
+}